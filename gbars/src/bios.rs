@@ -0,0 +1,881 @@
+use crate::bus::{Access, AccessKind, Bus};
+use crate::cpu::Cpu;
+
+use log::{debug, info, warn};
+
+// Written by the game's interrupt handler, acknowledged by IntrWait
+const BIOS_INTERRUPT_FLAGS: u32 = 0x03007FF8;
+const HALTCNT: u32 = 0x04000301;
+// Non-zero makes SoftReset return to EWRAM instead of the cartridge
+const RETURN_ADDRESS_SELECT: u32 = 0x03007FFA;
+
+// The real BIOS returns from a SWI having fetched from here
+const SWI_RETURN_FETCH: u32 = 0x190;
+
+// The BIOS's own memory accesses, which go through the bus like the game's
+trait BiosAccess: Bus {
+    fn load_8(&mut self, addr: u32) -> u8 {
+        self.read_8(addr, Access::NonSequential, AccessKind::Data)
+    }
+
+    fn load_16(&mut self, addr: u32) -> u16 {
+        self.read_16(addr, Access::NonSequential, AccessKind::Data)
+    }
+
+    fn load_32(&mut self, addr: u32) -> u32 {
+        self.read_32(addr, Access::NonSequential, AccessKind::Data)
+    }
+
+    fn store_8(&mut self, addr: u32, val: u8) {
+        self.write_8(addr, val, Access::NonSequential)
+    }
+
+    fn store_16(&mut self, addr: u32, val: u16) {
+        self.write_16(addr, val, Access::NonSequential)
+    }
+
+    fn store_32(&mut self, addr: u32, val: u32) {
+        self.write_32(addr, val, Access::NonSequential)
+    }
+}
+
+impl<B: Bus> BiosAccess for B {}
+
+// Stands in for the BIOS image. Only the IRQ vector has code, which calls the game's handler
+// the same way the real BIOS does. The rest is empty apart from the opcode open bus reads see
+// after a SWI
+pub fn hle_bios_image() -> Vec<u8> {
+    let irq_handler = [
+        0xE92D500F, // stmfd sp!, {r0-r3, r12, lr}
+        0xE3A00301, // mov r0, #0x04000000
+        0xE28FE000, // add lr, pc, #0
+        0xE510F004, // ldr pc, [r0, #-4]
+        0xE8BD500F, // ldmfd sp!, {r0-r3, r12, lr}
+        0xE25EF004, // subs pc, lr, #4
+    ];
+
+    let mut image = vec![0; 0x4000];
+
+    let mut write = |addr: usize, op: u32| {
+        image[addr..addr + 4].copy_from_slice(&u32::to_le_bytes(op));
+    };
+
+    for (i, op) in irq_handler.iter().enumerate() {
+        write(0x18 + i * 4, *op);
+    }
+    write(SWI_RETURN_FETCH as usize, 0xE3A02004); // mov r2, #4
+
+    image
+}
+
+// State kept across SWI calls when the BIOS is emulated rather than loaded
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct HleState {
+    intr_waiting: bool, // Set while IntrWait is spinning, so the old flags are only discarded once
+}
+
+// Runs the BIOS function a SWI asks for in place of the vector at 0x08.
+// Registers are updated as the real BIOS would leave them, and execution continues after the SWI.
+// Returns the internal cycles taken, like execute
+pub fn software_interrupt<B: Bus>(cpu: &mut Cpu, mem: &mut B, number: u8) -> u32 {
+    info!("HLE bios call {:2x}", number);
+
+    match number {
+        0x00 => soft_reset(cpu, mem),
+        0x01 => register_ram_reset(mem, cpu.r0),
+        0x02 => mem.store_8(HALTCNT, 0x00),
+        0x03 => mem.store_8(HALTCNT, 0x80),
+        0x04 => intr_wait(cpu, mem, cpu.r0 != 0, cpu.r1 as u16),
+        0x05 => intr_wait(cpu, mem, true, 1),
+        0x06 => div(cpu, cpu.r0 as i32, cpu.r1 as i32),
+        0x07 => div(cpu, cpu.r1 as i32, cpu.r0 as i32),
+        0x08 => cpu.r0 = sqrt(cpu.r0),
+        0x09 => cpu.r0 = arctan(cpu.r0 as i32) as u32,
+        0x0A => cpu.r0 = arctan2(cpu.r0 as i16 as i32, cpu.r1 as i16 as i32) as u32,
+        0x0B => cpu_set(mem, cpu.r0, cpu.r1, cpu.r2),
+        0x0C => cpu_fast_set(mem, cpu.r0, cpu.r1, cpu.r2),
+        0x0D => cpu.r0 = 0xBAAE187F,
+        0x0E => bg_affine_set(mem, cpu.r0, cpu.r1, cpu.r2),
+        0x0F => obj_affine_set(mem, cpu.r0, cpu.r1, cpu.r2, cpu.r3),
+        0x10 => bit_unpack(mem, cpu.r0, cpu.r1, cpu.r2),
+        0x11..=0x18 => {
+            let (data, unit_size) = match number {
+                0x11 => (lz77_uncomp(mem, cpu.r0), 1),
+                0x12 => (lz77_uncomp(mem, cpu.r0), 2),
+                0x13 => (huff_uncomp(mem, cpu.r0), 4),
+                0x14 => (rl_uncomp(mem, cpu.r0), 1),
+                0x15 => (rl_uncomp(mem, cpu.r0), 2),
+                0x16 => (diff_unfilter(mem, cpu.r0, 1), 1),
+                0x17 => (diff_unfilter(mem, cpu.r0, 1), 2),
+                _ => (diff_unfilter(mem, cpu.r0, 2), 2),
+            };
+
+            write_output(mem, cpu.r1, &data, unit_size);
+        }
+        _ => warn!("Unsupported HLE bios call {:2x}", number),
+    }
+
+    // The real BIOS returns with this fetched, which is what protected BIOS reads see afterwards
+    mem.read_32(SWI_RETURN_FETCH, Access::Sequential, AccessKind::Opcode);
+
+    // Only the accesses made here are timed, not the real BIOS's own code
+    0
+}
+
+fn soft_reset<B: Bus>(cpu: &mut Cpu, mem: &mut B) {
+    let to_ewram = mem.load_8(RETURN_ADDRESS_SELECT) != 0;
+
+    for addr in (0x03007E00..0x03008000).step_by(4) {
+        mem.store_32(addr, 0);
+    }
+
+    cpu.skip_bios();
+
+    if to_ewram {
+        cpu.branch_to(0x02000000);
+    }
+}
+
+fn register_ram_reset<B: Bus>(mem: &mut B, flags: u32) {
+    let mut clear = |start: u32, end: u32| {
+        for addr in (start..end).step_by(4) {
+            mem.store_32(addr, 0);
+        }
+    };
+
+    if flags & 0b1 != 0 {
+        clear(0x02000000, 0x02040000);
+    }
+    if flags & 0b10 != 0 {
+        // The top of IWRAM holds the stacks and BIOS variables, so is left alone
+        clear(0x03000000, 0x03007E00);
+    }
+    if flags & 0b100 != 0 {
+        clear(0x05000000, 0x05000400);
+    }
+    if flags & 0b1000 != 0 {
+        clear(0x06000000, 0x06018000);
+    }
+    if flags & 0b1_0000 != 0 {
+        clear(0x07000000, 0x07000400);
+    }
+    if flags & 0b10_0000 != 0 {
+        clear(0x04000120, 0x04000160);
+    }
+    if flags & 0b100_0000 != 0 {
+        clear(0x04000060, 0x040000B0);
+    }
+    if flags & 0b1000_0000 != 0 {
+        clear(0x04000000, 0x04000060);
+        clear(0x040000B0, 0x04000120);
+        clear(0x04000200, 0x04000210);
+    }
+}
+
+// Waits until one of the interrupts in mask has been flagged by the game's handler.
+// Waiting is done by running the SWI again until the flag shows up
+fn intr_wait<B: Bus>(cpu: &mut Cpu, mem: &mut B, discard_old: bool, mask: u16) {
+    let state = cpu.hle_state().expect("HLE bios call without HLE bios");
+    let first_call = !state.intr_waiting;
+
+    if first_call {
+        // Interrupts have to reach the handler for the flags to ever be set
+        mem.store_16(0x04000208, 1);
+
+        if discard_old {
+            let flags = mem.load_16(BIOS_INTERRUPT_FLAGS);
+            mem.store_16(BIOS_INTERRUPT_FLAGS, flags & !mask);
+        }
+    }
+
+    let flags = mem.load_16(BIOS_INTERRUPT_FLAGS);
+    let waiting = flags & mask == 0;
+
+    if waiting {
+        // Sleep until an interrupt, then run the SWI again once the handler is done
+        let swi_addr = cpu.r15.wrapping_sub(2 * cpu.instruction_size());
+        cpu.branch_to(swi_addr);
+
+        mem.store_8(HALTCNT, 0x00);
+    } else {
+        mem.store_16(BIOS_INTERRUPT_FLAGS, flags & !mask);
+    }
+
+    if let Some(state) = cpu.hle_state() {
+        state.intr_waiting = waiting;
+    }
+}
+
+fn div(cpu: &mut Cpu, numerator: i32, denominator: i32) {
+    if denominator == 0 {
+        // The real BIOS never returns from this
+        warn!("Division of {} by zero", numerator);
+
+        cpu.r0 = if numerator < 0 { -1i32 as u32 } else { 1 };
+        cpu.r1 = numerator as u32;
+        cpu.r3 = 1;
+        return;
+    }
+
+    let quotient = numerator.wrapping_div(denominator);
+
+    cpu.r0 = quotient as u32;
+    cpu.r1 = numerator.wrapping_rem(denominator) as u32;
+    cpu.r3 = quotient.wrapping_abs() as u32;
+}
+
+fn sqrt(value: u32) -> u32 {
+    let value = value as u64;
+    let mut result = (value as f64).sqrt() as u64;
+
+    // Correct for any rounding in the float result
+    while result * result > value {
+        result -= 1;
+    }
+    while (result + 1) * (result + 1) <= value {
+        result += 1;
+    }
+
+    result as u32
+}
+
+// The BIOS polynomial approximation, taking a 1.14 tangent and returning -0x4000..0x4000 for -pi/2..pi/2
+fn arctan(tan: i32) -> i32 {
+    let a = -(tan.wrapping_mul(tan) >> 14);
+
+    // The BIOS uses 32-bit multiplies, which wrap for tangents past 1
+    let mut b = (0xA9i32.wrapping_mul(a) >> 14) + 0x390;
+    for c in &[0x91C, 0xFB6, 0x16AA, 0x2081, 0x3651, 0xA2F9] {
+        b = (b.wrapping_mul(a) >> 14).wrapping_add(*c);
+    }
+
+    tan.wrapping_mul(b) >> 16
+}
+
+// Angle of the vector (x, y), with 0x10000 as a full turn
+fn arctan2(x: i32, y: i32) -> u16 {
+    let angle = if y == 0 {
+        if x >= 0 {
+            0
+        } else {
+            0x8000
+        }
+    } else if x == 0 {
+        if y >= 0 {
+            0x4000
+        } else {
+            0xC000
+        }
+    } else if y >= 0 {
+        if x >= 0 && x >= y {
+            arctan((y << 14) / x)
+        } else if x < 0 && -x >= y {
+            arctan((y << 14) / x) + 0x8000
+        } else {
+            0x4000 - arctan((x << 14) / y)
+        }
+    } else if x <= 0 && -x > -y {
+        arctan((y << 14) / x) + 0x8000
+    } else if x > 0 && x >= -y {
+        arctan((y << 14) / x) + 0x10000
+    } else {
+        0xC000 - arctan((x << 14) / y)
+    };
+
+    angle as u16
+}
+
+// The BIOS refuses to copy out of itself
+fn source_is_bios(src: u32) -> bool {
+    let protected = src & 0x0E000000 == 0;
+
+    if protected {
+        debug!("Ignoring bios copy from {:8x}", src);
+    }
+
+    protected
+}
+
+fn cpu_set<B: Bus>(mem: &mut B, src: u32, dst: u32, control: u32) {
+    if source_is_bios(src) {
+        return;
+    }
+
+    let count = control & 0x1FFFFF;
+    let fill = control & (0b1 << 24) != 0;
+    let words = control & (0b1 << 26) != 0;
+
+    if words {
+        let (src, dst) = (src & !0b11, dst & !0b11);
+
+        for i in 0..count {
+            let from = if fill { src } else { src + i * 4 };
+            let val = mem.load_32(from);
+            mem.store_32(dst + i * 4, val);
+        }
+    } else {
+        let (src, dst) = (src & !0b1, dst & !0b1);
+
+        for i in 0..count {
+            let from = if fill { src } else { src + i * 2 };
+            let val = mem.load_16(from);
+            mem.store_16(dst + i * 2, val);
+        }
+    }
+}
+
+// Word copies only, in blocks of 8 words
+fn cpu_fast_set<B: Bus>(mem: &mut B, src: u32, dst: u32, control: u32) {
+    if source_is_bios(src) {
+        return;
+    }
+
+    let count = ((control & 0x1FFFFF) + 7) & !7;
+    let fill = control & (0b1 << 24) != 0;
+    let (src, dst) = (src & !0b11, dst & !0b11);
+
+    for i in 0..count {
+        let from = if fill { src } else { src + i * 4 };
+        let val = mem.load_32(from);
+        mem.store_32(dst + i * 4, val);
+    }
+}
+
+// The top byte of a BIOS angle is a fraction of a full turn
+fn angle_to_radians(angle: u16) -> f64 {
+    (angle >> 8) as f64 / 128.0 * std::f64::consts::PI
+}
+
+fn bg_affine_set<B: Bus>(mem: &mut B, src: u32, dst: u32, count: u32) {
+    for i in 0..count {
+        let src = src + i * 20;
+        let dst = dst + i * 16;
+
+        // Texture origin in 8.8 fixed point, screen origin in pixels
+        let origin_x = mem.load_32(src) as i32 as f64 / 256.0;
+        let origin_y = mem.load_32(src + 4) as i32 as f64 / 256.0;
+        let screen_x = mem.load_16(src + 8) as i16 as f64;
+        let screen_y = mem.load_16(src + 10) as i16 as f64;
+        let scale_x = mem.load_16(src + 12) as i16 as f64 / 256.0;
+        let scale_y = mem.load_16(src + 14) as i16 as f64 / 256.0;
+        let theta = angle_to_radians(mem.load_16(src + 16));
+
+        let pa = theta.cos() * scale_x;
+        let pb = -theta.sin() * scale_x;
+        let pc = theta.sin() * scale_y;
+        let pd = theta.cos() * scale_y;
+
+        let start_x = origin_x - (pa * screen_x + pb * screen_y);
+        let start_y = origin_y - (pc * screen_x + pd * screen_y);
+
+        mem.store_16(dst, (pa * 256.0) as i16 as u16);
+        mem.store_16(dst + 2, (pb * 256.0) as i16 as u16);
+        mem.store_16(dst + 4, (pc * 256.0) as i16 as u16);
+        mem.store_16(dst + 6, (pd * 256.0) as i16 as u16);
+        mem.store_32(dst + 8, (start_x * 256.0) as i32 as u32);
+        mem.store_32(dst + 12, (start_y * 256.0) as i32 as u32);
+    }
+}
+
+// Each parameter is written stride bytes apart, so OAM can be targeted directly with a stride of 8
+fn obj_affine_set<B: Bus>(mem: &mut B, src: u32, dst: u32, count: u32, stride: u32) {
+    for i in 0..count {
+        let src = src + i * 8;
+        let dst = dst + i * stride * 4;
+
+        let scale_x = mem.load_16(src) as i16 as f64 / 256.0;
+        let scale_y = mem.load_16(src + 2) as i16 as f64 / 256.0;
+        let theta = angle_to_radians(mem.load_16(src + 4));
+
+        let params = [
+            theta.cos() * scale_x,
+            -theta.sin() * scale_x,
+            theta.sin() * scale_y,
+            theta.cos() * scale_y,
+        ];
+
+        for (n, param) in params.iter().enumerate() {
+            mem.store_16(dst + n as u32 * stride, (param * 256.0) as i16 as u16);
+        }
+    }
+}
+
+fn bit_unpack<B: Bus>(mem: &mut B, src: u32, dst: u32, info: u32) {
+    let length = mem.load_16(info) as u32;
+    let src_width = mem.load_8(info + 2) as u32;
+    let dst_width = mem.load_8(info + 3) as u32;
+    let offset = mem.load_32(info + 4);
+    let offset_zero = offset & (0b1 << 31) != 0;
+    let offset = offset & !(0b1 << 31);
+
+    if ![1, 2, 4, 8].contains(&src_width) || ![1, 2, 4, 8, 16, 32].contains(&dst_width) {
+        warn!("Invalid BitUnPack widths {} -> {}", src_width, dst_width);
+        return;
+    }
+
+    let src_mask = (1u32 << src_width) - 1;
+    let mut out = 0u32;
+    let mut out_bits = 0;
+    let mut dst = dst;
+
+    for i in 0..length {
+        let byte = mem.load_8(src + i) as u32;
+
+        for shift in (0..8).step_by(src_width as usize) {
+            let mut unit = (byte >> shift) & src_mask;
+
+            if unit != 0 || offset_zero {
+                unit = unit.wrapping_add(offset);
+            }
+
+            out |= unit.checked_shl(out_bits).unwrap_or(0);
+            out_bits += dst_width;
+
+            if out_bits == 32 {
+                mem.store_32(dst, out);
+                dst += 4;
+                out = 0;
+                out_bits = 0;
+            }
+        }
+    }
+}
+
+// Compressed data starts with a header word holding the type and the decompressed size
+fn read_header<B: Bus>(mem: &mut B, src: u32) -> (u32, usize) {
+    let header = mem.load_32(src);
+
+    (header & 0xFF, (header >> 8) as usize)
+}
+
+fn lz77_uncomp<B: Bus>(mem: &mut B, src: u32) -> Vec<u8> {
+    let (_, size) = read_header(mem, src);
+    let mut out = Vec::with_capacity(size);
+    let mut src = src + 4;
+
+    while out.len() < size {
+        let flags = mem.load_8(src);
+        src += 1;
+
+        for bit in (0..8).rev() {
+            if out.len() >= size {
+                break;
+            }
+
+            if flags & (0b1 << bit) == 0 {
+                out.push(mem.load_8(src));
+                src += 1;
+            } else {
+                let first = mem.load_8(src) as usize;
+                let second = mem.load_8(src + 1) as usize;
+                src += 2;
+
+                let length = (first >> 4) + 3;
+                let disp = (((first & 0xF) << 8) | second) + 1;
+
+                for _ in 0..length {
+                    let val = out.len().checked_sub(disp).map_or(0, |from| out[from]);
+                    out.push(val);
+                }
+            }
+        }
+    }
+
+    out.truncate(size);
+    out
+}
+
+fn huff_uncomp<B: Bus>(mem: &mut B, src: u32) -> Vec<u8> {
+    let (header, size) = read_header(mem, src);
+    let symbol_bits = header & 0xF;
+
+    if symbol_bits != 4 && symbol_bits != 8 {
+        warn!("Unsupported huffman symbol size {}", symbol_bits);
+        return vec![];
+    }
+
+    // Each node is a byte: child offset in bits 0-5, and whether the right/left child is a leaf in bits 6/7
+    let root = src + 5;
+    let mut stream = src + 4 + (mem.load_8(src + 4) as u32 + 1) * 2;
+
+    let mut out = Vec::with_capacity(size);
+    let mut packed = 0u32;
+    let mut packed_bits = 0;
+    let mut node_addr = root;
+
+    while out.len() < size {
+        let bits = mem.load_32(stream);
+        stream += 4;
+
+        for bit in (0..32).rev() {
+            let node = mem.load_8(node_addr);
+            let right = (bits >> bit) & 0b1;
+            let child = (node_addr & !0b1) + (node as u32 & 0x3F) * 2 + 2 + right;
+            let leaf = node & (0b1000_0000 >> right) != 0;
+
+            if !leaf {
+                node_addr = child;
+                continue;
+            }
+
+            packed |= (mem.load_8(child) as u32) << packed_bits;
+            packed_bits += symbol_bits;
+            node_addr = root;
+
+            if packed_bits == 32 {
+                out.extend_from_slice(&packed.to_le_bytes());
+                packed = 0;
+                packed_bits = 0;
+
+                if out.len() >= size {
+                    break;
+                }
+            }
+        }
+    }
+
+    out.truncate(size);
+    out
+}
+
+fn rl_uncomp<B: Bus>(mem: &mut B, src: u32) -> Vec<u8> {
+    let (_, size) = read_header(mem, src);
+    let mut out = Vec::with_capacity(size);
+    let mut src = src + 4;
+
+    while out.len() < size {
+        let flag = mem.load_8(src);
+        src += 1;
+
+        if flag & 0x80 != 0 {
+            let length = (flag & 0x7F) as usize + 3;
+            let val = mem.load_8(src);
+            src += 1;
+
+            out.resize(out.len() + length, val);
+        } else {
+            let length = (flag & 0x7F) as u32 + 1;
+
+            for i in 0..length {
+                out.push(mem.load_8(src + i));
+            }
+            src += length;
+        }
+    }
+
+    out.truncate(size);
+    out
+}
+
+// Each unit of unit_size bytes is stored as the difference from the previous one
+fn diff_unfilter<B: Bus>(mem: &mut B, src: u32, unit_size: u32) -> Vec<u8> {
+    let (_, size) = read_header(mem, src);
+    let mut out = Vec::with_capacity(size);
+    let mut previous = 0u16;
+
+    for i in 0..(size as u32 / unit_size) {
+        let addr = src + 4 + i * unit_size;
+
+        if unit_size == 1 {
+            previous = (previous as u8).wrapping_add(mem.load_8(addr)) as u16;
+            out.push(previous as u8);
+        } else {
+            previous = previous.wrapping_add(mem.load_16(addr));
+            out.extend_from_slice(&previous.to_le_bytes());
+        }
+    }
+
+    out
+}
+
+// Writes in units of unit_size bytes, as VRAM can't take byte writes.
+// A trailing partial unit is never written
+fn write_output<B: Bus>(mem: &mut B, dst: u32, data: &[u8], unit_size: usize) {
+    for (i, unit) in data.chunks_exact(unit_size).enumerate() {
+        let addr = dst + (i * unit_size) as u32;
+
+        match unit {
+            [byte] => mem.store_8(addr, *byte),
+            [low, high] => mem.store_16(addr, u16::from_le_bytes([*low, *high])),
+            [a, b, c, d] => mem.store_32(addr, u32::from_le_bytes([*a, *b, *c, *d])),
+            _ => unreachable!(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::{CpuState, Mode};
+    use crate::memory::Memory;
+    use crate::system::System;
+
+    fn hle() -> (Cpu, Memory) {
+        let mut cpu = Cpu::new();
+        cpu.skip_bios();
+        cpu.set_hle_bios(true);
+
+        (cpu, Memory::new_with_rom(vec![]))
+    }
+
+    fn write_bytes(mem: &mut Memory, addr: u32, data: &[u8]) {
+        for (i, byte) in data.iter().enumerate() {
+            mem.set_byte(addr + i as u32, *byte);
+        }
+    }
+
+    fn read_bytes(mem: &Memory, addr: u32, length: u32) -> Vec<u8> {
+        (0..length).map(|i| mem.get_byte(addr + i)).collect()
+    }
+
+    #[test]
+    fn test_skip_bios() {
+        let (cpu, _) = hle();
+
+        assert_eq!(Mode::System, cpu.mode());
+        assert_eq!(CpuState::Arm, cpu.state());
+        assert_eq!(0x08000000, cpu.r15);
+        assert_eq!(0x03007F00, cpu.r13);
+    }
+
+    #[test]
+    fn test_div() {
+        let (mut cpu, mut mem) = hle();
+
+        cpu.r0 = -7i32 as u32;
+        cpu.r1 = 2;
+        software_interrupt(&mut cpu, &mut mem, 0x06);
+
+        assert_eq!(-3i32 as u32, cpu.r0);
+        assert_eq!(-1i32 as u32, cpu.r1);
+        assert_eq!(3, cpu.r3);
+
+        // DivArm swaps the operands
+        cpu.r0 = 2;
+        cpu.r1 = 7;
+        software_interrupt(&mut cpu, &mut mem, 0x07);
+
+        assert_eq!(3, cpu.r0);
+        assert_eq!(1, cpu.r1);
+    }
+
+    #[test]
+    fn test_sqrt_and_arctan() {
+        assert_eq!(0, sqrt(0));
+        assert_eq!(4, sqrt(24));
+        assert_eq!(5, sqrt(25));
+        assert_eq!(0xFFFF, sqrt(0xFFFFFFFF));
+
+        assert_eq!(0, arctan(0));
+        // atan(1) is pi/4, a quarter of 0x8000
+        assert!((arctan(0x4000) - 0x2000).abs() < 8);
+
+        // Steeper than 45 degrees the polynomial overflows, as on hardware
+        let (mut cpu, mut mem) = hle();
+        cpu.r0 = 0x8000;
+        software_interrupt(&mut cpu, &mut mem, 0x09);
+        assert_eq!(0x16A2, cpu.r0);
+        assert_eq!(0x7FFF, arctan(0x7FFFFFFF));
+
+        assert_eq!(0, arctan2(0x100, 0));
+        assert_eq!(0x4000, arctan2(0, 0x100));
+        assert_eq!(0xC000, arctan2(0, -0x100));
+        assert!((arctan2(-0x100, -0x100) as i32 - 0xA000).abs() < 8);
+    }
+
+    #[test]
+    fn test_cpu_set() {
+        let (mut cpu, mut mem) = hle();
+
+        mem.set_word(0x02000000, 0x11223344);
+        mem.set_word(0x02000004, 0x55667788);
+
+        // Halfword copy of 3 units
+        cpu.r0 = 0x02000000;
+        cpu.r1 = 0x02000100;
+        cpu.r2 = 3;
+        software_interrupt(&mut cpu, &mut mem, 0x0B);
+
+        assert_eq!(0x11223344, mem.get_word(0x02000100));
+        assert_eq!(0x7788, mem.get_word(0x02000104));
+
+        // Word fill
+        cpu.r1 = 0x02000200;
+        cpu.r2 = (0b1 << 26) | (0b1 << 24) | 2;
+        software_interrupt(&mut cpu, &mut mem, 0x0B);
+
+        assert_eq!(0x11223344, mem.get_word(0x02000200));
+        assert_eq!(0x11223344, mem.get_word(0x02000204));
+
+        // CpuFastSet rounds up to 8 words
+        cpu.r1 = 0x02000300;
+        cpu.r2 = (0b1 << 24) | 1;
+        software_interrupt(&mut cpu, &mut mem, 0x0C);
+
+        assert_eq!(0x11223344, mem.get_word(0x0200031C));
+        assert_eq!(0, mem.get_word(0x02000320));
+    }
+
+    #[test]
+    fn test_lz77_uncomp() {
+        let (mut cpu, mut mem) = hle();
+
+        // "abcabcabcd": 3 literals, a 6 byte copy from 3 back, then a literal
+        write_bytes(
+            &mut mem,
+            0x02000000,
+            &[
+                0x10,
+                10,
+                0,
+                0,
+                0b0001_0000,
+                b'a',
+                b'b',
+                b'c',
+                0x30,
+                0x02,
+                b'd',
+            ],
+        );
+
+        cpu.r0 = 0x02000000;
+        cpu.r1 = 0x02001000;
+        software_interrupt(&mut cpu, &mut mem, 0x11);
+
+        assert_eq!(b"abcabcabcd".to_vec(), read_bytes(&mem, 0x02001000, 10));
+    }
+
+    #[test]
+    fn test_rl_uncomp() {
+        let (mut cpu, mut mem) = hle();
+
+        // 2 literals then a run of 4
+        write_bytes(&mut mem, 0x02000000, &[0x30, 6, 0, 0, 0x01, 1, 2, 0x81, 9]);
+
+        cpu.r0 = 0x02000000;
+        cpu.r1 = 0x02001000;
+        software_interrupt(&mut cpu, &mut mem, 0x15);
+
+        assert_eq!(vec![1, 2, 9, 9, 9, 9], read_bytes(&mem, 0x02001000, 6));
+    }
+
+    #[test]
+    fn test_huff_uncomp() {
+        let (mut cpu, mut mem) = hle();
+
+        // 8-bit symbols, a root with leaves 'x' (0) and 'y' (1)
+        write_bytes(
+            &mut mem,
+            0x02000000,
+            &[0x28, 4, 0, 0, 0x01, 0b1100_0000, b'x', b'y'],
+        );
+        // Bitstream yxxy
+        mem.set_word(0x02000008, 0b1001 << 28);
+
+        cpu.r0 = 0x02000000;
+        cpu.r1 = 0x02001000;
+        software_interrupt(&mut cpu, &mut mem, 0x13);
+
+        assert_eq!(b"yxxy".to_vec(), read_bytes(&mem, 0x02001000, 4));
+    }
+
+    #[test]
+    fn test_diff_unfilter() {
+        let (mut cpu, mut mem) = hle();
+
+        write_bytes(&mut mem, 0x02000000, &[0x81, 4, 0, 0, 5, 1, 0xff, 2]);
+
+        cpu.r0 = 0x02000000;
+        cpu.r1 = 0x02001000;
+        software_interrupt(&mut cpu, &mut mem, 0x16);
+
+        assert_eq!(vec![5, 6, 5, 7], read_bytes(&mem, 0x02001000, 4));
+
+        write_bytes(
+            &mut mem,
+            0x02000000,
+            &[0x82, 4, 0, 0, 0x00, 0x10, 0x01, 0x00],
+        );
+        software_interrupt(&mut cpu, &mut mem, 0x18);
+
+        assert_eq!(0x1001_1000, mem.get_word(0x02001000));
+    }
+
+    #[test]
+    fn test_bit_unpack() {
+        let (mut cpu, mut mem) = hle();
+
+        // 1 bit to 4 bit, adding 2 to set bits
+        mem.set_byte(0x02000000, 0b0000_0101);
+        mem.set_halfword(0x02000010, 1);
+        mem.set_byte(0x02000012, 1);
+        mem.set_byte(0x02000013, 4);
+        mem.set_word(0x02000014, 2);
+
+        cpu.r0 = 0x02000000;
+        cpu.r1 = 0x02001000;
+        cpu.r2 = 0x02000010;
+        software_interrupt(&mut cpu, &mut mem, 0x10);
+
+        assert_eq!(0x0000_0303, mem.get_word(0x02001000));
+    }
+
+    #[test]
+    fn test_obj_affine_set_identity() {
+        let (mut cpu, mut mem) = hle();
+
+        mem.set_halfword(0x02000000, 0x100);
+        mem.set_halfword(0x02000002, 0x100);
+        mem.set_halfword(0x02000004, 0);
+
+        cpu.r0 = 0x02000000;
+        cpu.r1 = 0x02001000;
+        cpu.r2 = 1;
+        cpu.r3 = 8;
+        software_interrupt(&mut cpu, &mut mem, 0x0F);
+
+        assert_eq!(0x100, mem.get_halfword(0x02001000));
+        assert_eq!(0, mem.get_halfword(0x02001008));
+        assert_eq!(0, mem.get_halfword(0x02001010));
+        assert_eq!(0x100, mem.get_halfword(0x02001018));
+    }
+
+    #[test]
+    fn test_intr_wait_repeats_until_flagged() {
+        let (mut cpu, mut mem) = hle();
+
+        mem.set_halfword(BIOS_INTERRUPT_FLAGS, 0b1);
+
+        // VBlankIntrWait at 0x08000100 discards the stale vblank flag and waits
+        cpu.r15 = 0x08000108;
+        software_interrupt(&mut cpu, &mut mem, 0x05);
+
+        assert_eq!(0x08000100, cpu.r15);
+        assert_eq!(0, mem.get_halfword(BIOS_INTERRUPT_FLAGS));
+        assert!(mem.halted());
+
+        // A handler flags vblank, and the repeated call returns and acknowledges it
+        mem.set_halfword(BIOS_INTERRUPT_FLAGS, 0b1);
+        cpu.r15 = 0x08000108;
+        software_interrupt(&mut cpu, &mut mem, 0x05);
+
+        assert_eq!(0x08000108, cpu.r15);
+        assert_eq!(0, mem.get_halfword(BIOS_INTERRUPT_FLAGS));
+    }
+
+    #[test]
+    fn test_soft_reset() {
+        let (mut cpu, mut mem) = hle();
+
+        cpu.set_cpsr(0x12);
+        cpu.r0 = 5;
+        mem.set_word(0x03007E00, 0xffffffff);
+        software_interrupt(&mut cpu, &mut mem, 0x00);
+
+        assert_eq!(Mode::System, cpu.mode());
+        assert_eq!(0x08000000, cpu.r15);
+        assert_eq!(0, cpu.r0);
+        assert_eq!(0, mem.get_word(0x03007E00));
+    }
+}
@@ -0,0 +1,49 @@
+// Whether an access follows on from the previous one at the next address.
+// Sequential accesses are cheaper on most of the GBA's memory
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Access {
+    NonSequential,
+    Sequential,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum AccessKind {
+    Opcode, // Instruction fetch
+    Data,
+}
+
+// Everything the cpu can see through its memory interface.
+// Halfword and word accesses are passed the address the cpu used, and are force aligned by the bus
+pub trait Bus {
+    fn read_8(&mut self, addr: u32, access: Access, kind: AccessKind) -> u8;
+    fn read_16(&mut self, addr: u32, access: Access, kind: AccessKind) -> u16;
+    fn read_32(&mut self, addr: u32, access: Access, kind: AccessKind) -> u32;
+
+    fn write_8(&mut self, addr: u32, val: u8, access: Access);
+    fn write_16(&mut self, addr: u32, val: u16, access: Access);
+    fn write_32(&mut self, addr: u32, val: u32, access: Access);
+
+    // Internal cycles where the cpu doesn't use the bus
+    fn idle(&mut self, cycles: u32);
+
+    // Running total of clock cycles spent on accesses and idling
+    fn cycles(&self) -> u64;
+
+    // SWP reads then writes the same address without releasing the bus in between.
+    // Returns the old value, rotated as for LDR if addr is misaligned
+    fn swap_32(&mut self, addr: u32, val: u32) -> u32 {
+        let old = self
+            .read_32(addr, Access::NonSequential, AccessKind::Data)
+            .rotate_right(8 * (addr & 0b11));
+        self.write_32(addr, val, Access::NonSequential);
+
+        old
+    }
+
+    fn swap_8(&mut self, addr: u32, val: u8) -> u8 {
+        let old = self.read_8(addr, Access::NonSequential, AccessKind::Data);
+        self.write_8(addr, val, Access::NonSequential);
+
+        old
+    }
+}
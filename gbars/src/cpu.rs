@@ -1,10 +1,21 @@
+use crate::bios::HleState;
+use crate::bus::{Access, AccessKind};
 use crate::instruction::Instruction;
-use crate::memory::Memory;
 use crate::execute;
+use crate::system::System;
 
 use log::{info, debug};
 use std::fmt;
 
+const FLAG_N: u32 = 0b1 << 31;
+const FLAG_Z: u32 = 0b1 << 30;
+const FLAG_C: u32 = 0b1 << 29;
+const FLAG_V: u32 = 0b1 << 28;
+pub(crate) const FLAG_T: u32 = 0b1 << 5;
+const FLAG_F: u32 = 0b1 << 6;
+const FLAG_I: u32 = 0b1 << 7;
+const MODE_MASK: u32 = 0b11111;
+
 #[derive(Debug, PartialEq, Eq)]
 pub struct Cpu {
     state: CpuState,
@@ -26,20 +37,129 @@ pub struct Cpu {
     pub r15: u32, // pc
     cpsr: u32,
 
+    // Registers belonging to modes other than the current one.
+    // The visible r8-r14 are swapped in and out of these on a mode switch
+    r8_r12_banks: [[u32; 5]; 2],  // user (shared by every non-FIQ mode), FIQ
+    r13_r14_banks: [[u32; 2]; 6], // indexed by Mode::bank
+    spsr_banks: [u32; 6],         // indexed by Mode::bank, user/system have no SPSR
+
     // internal state
     fetched: Option<u32>, // Could be 16- or 32-bits (thumb/arm)
     decoded: Option<u32>,
+
+    hle_bios: Option<HleState>, // Set when SWIs are emulated instead of running BIOS code
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
 pub enum CpuState {
     Arm,
     Thumb,
 }
 
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Exception {
+    Reset,
+    Undefined,
+    SoftwareInterrupt,
+    PrefetchAbort,
+    DataAbort,
+    Irq,
+    Fiq,
+}
+
+impl Exception {
+    fn vector(self) -> u32 {
+        match self {
+            Exception::Reset => 0x00,
+            Exception::Undefined => 0x04,
+            Exception::SoftwareInterrupt => 0x08,
+            Exception::PrefetchAbort => 0x0C,
+            Exception::DataAbort => 0x10,
+            Exception::Irq => 0x18,
+            Exception::Fiq => 0x1C,
+        }
+    }
+
+    fn mode(self) -> Mode {
+        match self {
+            Exception::Reset | Exception::SoftwareInterrupt => Mode::Supervisor,
+            Exception::Undefined => Mode::Undefined,
+            Exception::PrefetchAbort | Exception::DataAbort => Mode::Abort,
+            Exception::Irq => Mode::Irq,
+            Exception::Fiq => Mode::Fiq,
+        }
+    }
+
+    // Offset of the saved lr from the address of the instruction the exception was taken on.
+    // Chosen so each handler's usual return (MOVS PC, LR or SUBS PC, LR, #4/#8) lands correctly
+    fn return_offset(self, instruction_size: u32) -> u32 {
+        match self {
+            Exception::Reset => 0,
+            Exception::Undefined | Exception::SoftwareInterrupt => instruction_size,
+            Exception::PrefetchAbort | Exception::Irq | Exception::Fiq => 4,
+            Exception::DataAbort => 8,
+        }
+    }
+}
+
+enum UserBankSlot {
+    R8R12(usize),
+    R13R14(usize),
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Mode {
+    User,
+    Fiq,
+    Irq,
+    Supervisor,
+    Abort,
+    Undefined,
+    System,
+}
+
+impl Mode {
+    pub fn from_bits(bits: u32) -> Option<Mode> {
+        match bits & MODE_MASK {
+            0b10000 => Some(Mode::User),
+            0b10001 => Some(Mode::Fiq),
+            0b10010 => Some(Mode::Irq),
+            0b10011 => Some(Mode::Supervisor),
+            0b10111 => Some(Mode::Abort),
+            0b11011 => Some(Mode::Undefined),
+            0b11111 => Some(Mode::System),
+            _ => None,
+        }
+    }
+
+    pub fn bits(self) -> u32 {
+        match self {
+            Mode::User => 0b10000,
+            Mode::Fiq => 0b10001,
+            Mode::Irq => 0b10010,
+            Mode::Supervisor => 0b10011,
+            Mode::Abort => 0b10111,
+            Mode::Undefined => 0b11011,
+            Mode::System => 0b11111,
+        }
+    }
+
+    // User and System share every register
+    fn bank(self) -> usize {
+        match self {
+            Mode::User | Mode::System => 0,
+            Mode::Fiq => 1,
+            Mode::Irq => 2,
+            Mode::Supervisor => 3,
+            Mode::Abort => 4,
+            Mode::Undefined => 5,
+        }
+    }
+}
+
 // Registers as seen by the program
-// These have no concept of banking
-#[derive(Debug, PartialEq, Eq)]
+// These name a register, which resolves to the current mode's bank when it's read or written
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
 pub enum Register {
     R0,
     R1,
@@ -83,18 +203,71 @@ impl Cpu {
             r15: 0, // pc
             cpsr: initial_cpsr,
 
+            r8_r12_banks: [[0; 5]; 2],
+            r13_r14_banks: [[0; 2]; 6],
+            spsr_banks: [0; 6],
+
             // internal state
             fetched: None, // Could be 16- or 32-bits (thumb/arm)
             decoded: None,
+            hle_bios: None,
+        }
+    }
+
+    // Runs for at least the given number of clock cycles, stopping for each event as it's due
+    pub fn run<B: System>(&mut self, bus: &mut B, cycles: u64) {
+        let end = bus.cycles() + cycles;
+
+        while bus.cycles() < end {
+            let until = bus.next_event().min(end);
+
+            while bus.cycles() < until {
+                self.step(bus, until);
+            }
+
+            bus.run_events();
         }
     }
 
-    pub fn cycle(&mut self, mem: &mut Memory) {
+    // Advances the pipeline by one step.
+    // Returns the clock cycles taken by the step, as counted by the bus
+    pub fn cycle<B: System>(&mut self, bus: &mut B) -> u32 {
+        self.step(bus, u64::MAX)
+    }
+
+    // A pipeline step that, while halted, sleeps until the next event but no later than limit
+    fn step<B: System>(&mut self, bus: &mut B, limit: u64) -> u32 {
+        let start = bus.cycles();
+
+        // Nothing runs until an interrupt wakes the system up, which can only come from an event
+        if bus.halted() {
+            let until = bus.next_event().min(limit).saturating_sub(start);
+            bus.idle(until.max(1).min(u32::MAX as u64) as u32);
+
+            return (bus.cycles() - start) as u32;
+        }
+
         let prev_fetched = self.fetched;
         let prev_decoded = self.decoded;
 
-        // fetch
-        let new_fetch = mem.get_word(self.r15);
+        // An IRQ is taken in place of the next instruction once the pipeline has one ready
+        if prev_decoded.is_some() && !self.irq_disabled() && bus.irq_line() {
+            self.enter_exception(Exception::Irq);
+
+            return (bus.cycles() - start) as u32;
+        }
+
+        // fetch, which only follows on from the last one if we didn't just jump
+        let access = if prev_fetched.is_some() {
+            Access::Sequential
+        } else {
+            Access::NonSequential
+        };
+
+        let new_fetch = match self.state {
+            CpuState::Arm => bus.read_32(self.r15, access, AccessKind::Opcode),
+            CpuState::Thumb => bus.read_16(self.r15, access, AccessKind::Opcode) as u32,
+        };
         self.fetched = Some(new_fetch);
 
         // decode
@@ -102,19 +275,25 @@ impl Cpu {
 
         if let Some(prev_decoded) = prev_decoded {
             // execute
-            // TODO Decode thumb
-            let instr = Instruction::decode_arm(prev_decoded);
+            let instr = match self.state {
+                CpuState::Arm => Instruction::decode_arm(prev_decoded),
+                CpuState::Thumb => Instruction::decode_thumb(prev_decoded as u16),
+            };
             info!("exec {:8x} {:?}", prev_decoded, instr);
 
-            execute::execute(self, instr);
+            // Memory accesses were timed by the bus as they happened, which leaves the internal cycles
+            let internal = execute::execute(self, bus, instr);
+            bus.idle(internal);
         } else {
             debug!("No execute");
         }
         
-        if let Some(fetched) = self.fetched {
+        if self.fetched.is_some() {
             // We didn't jump
-            self.r15 += 4;
+            self.r15 += self.instruction_size();
         }
+
+        (bus.cycles() - start) as u32
     }
 
     pub fn flush_pipeline(&mut self) {
@@ -122,6 +301,34 @@ impl Cpu {
         self.decoded = None;
     }
 
+    // Jump to addr, ignoring the bits below the current instruction alignment
+    pub fn branch_to(&mut self, addr: u32) {
+        self.r15 = addr & !(self.instruction_size() - 1);
+        self.flush_pipeline();
+    }
+
+    pub fn state(&self) -> CpuState {
+        self.state
+    }
+
+    // Switching state also updates the T bit of the CPSR
+    pub fn set_state(&mut self, state: CpuState) {
+        let cpsr = match state {
+            CpuState::Arm => self.cpsr & !FLAG_T,
+            CpuState::Thumb => self.cpsr | FLAG_T,
+        };
+
+        self.set_cpsr(cpsr);
+    }
+
+    // Size in bytes of an instruction in the current state
+    pub fn instruction_size(&self) -> u32 {
+        match self.state {
+            CpuState::Arm => 4,
+            CpuState::Thumb => 2,
+        }
+    }
+
     pub fn get_register(&self, reg: Register) -> u32{
         match reg {
             Register::R0 => self.r0,
@@ -142,6 +349,254 @@ impl Cpu {
             Register::R15 => self.r15,
         }
     }
+
+    pub fn set_register(&mut self, reg: Register, val: u32) {
+        match reg {
+            Register::R0 => self.r0 = val,
+            Register::R1 => self.r1 = val,
+            Register::R2 => self.r2 = val,
+            Register::R3 => self.r3 = val,
+            Register::R4 => self.r4 = val,
+            Register::R5 => self.r5 = val,
+            Register::R6 => self.r6 = val,
+            Register::R7 => self.r7 = val,
+            Register::R8 => self.r8 = val,
+            Register::R9 => self.r9 = val,
+            Register::R10 => self.r10 = val,
+            Register::R11 => self.r11 = val,
+            Register::R12 => self.r12 = val,
+            Register::R13 => self.r13 = val,
+            Register::R14 => self.r14 = val,
+            Register::R15 => self.r15 = val,
+        }
+    }
+
+    // Takes an exception on the instruction currently in the execute stage.
+    // For interrupts that is the instruction which hasn't run yet
+    pub fn enter_exception(&mut self, exception: Exception) {
+        let size = self.instruction_size();
+        let instr_addr = self.r15.wrapping_sub(2 * size);
+        let return_addr = instr_addr.wrapping_add(exception.return_offset(size));
+
+        let old_cpsr = self.cpsr;
+
+        // Exceptions are always handled in ARM state with IRQs disabled
+        let mut cpsr = (old_cpsr & !(MODE_MASK | FLAG_T)) | exception.mode().bits() | FLAG_I;
+
+        if matches!(exception, Exception::Reset | Exception::Fiq) {
+            cpsr |= FLAG_F;
+        }
+
+        self.set_cpsr(cpsr);
+        self.set_spsr(old_cpsr);
+        self.r14 = return_addr;
+
+        info!("Exception {:?} return {:8x}", exception, return_addr);
+
+        self.branch_to(exception.vector());
+    }
+
+    pub fn irq_disabled(&self) -> bool {
+        self.cpsr & FLAG_I != 0
+    }
+
+    // Runs BIOS calls directly instead of taking the SWI exception, for running without a BIOS
+    pub fn set_hle_bios(&mut self, enabled: bool) {
+        self.hle_bios = if enabled {
+            Some(HleState::default())
+        } else {
+            None
+        };
+    }
+
+    pub fn has_hle_bios(&self) -> bool {
+        self.hle_bios.is_some()
+    }
+
+    pub(crate) fn hle_state(&mut self) -> Option<&mut HleState> {
+        self.hle_bios.as_mut()
+    }
+
+    // Sets up the registers as the BIOS leaves them when it starts the cartridge
+    pub fn skip_bios(&mut self) {
+        let stacks = [
+            (Mode::Supervisor, 0x03007FE0),
+            (Mode::Irq, 0x03007FA0),
+            (Mode::System, 0x03007F00),
+        ];
+
+        for (mode, sp) in stacks.iter() {
+            self.set_cpsr(mode.bits());
+            self.set_spsr(0);
+            self.r13 = *sp;
+            self.r14 = 0;
+        }
+
+        self.r0 = 0;
+        self.r1 = 0;
+        self.r2 = 0;
+        self.r3 = 0;
+        self.r4 = 0;
+        self.r5 = 0;
+        self.r6 = 0;
+        self.r7 = 0;
+        self.r8 = 0;
+        self.r9 = 0;
+        self.r10 = 0;
+        self.r11 = 0;
+        self.r12 = 0;
+
+        self.branch_to(0x08000000);
+    }
+
+    // Registers as seen from User mode, regardless of the current mode
+    pub fn get_user_register(&self, reg: Register) -> u32 {
+        match self.user_bank_slot(reg) {
+            Some(UserBankSlot::R8R12(i)) => self.r8_r12_banks[0][i],
+            Some(UserBankSlot::R13R14(i)) => self.r13_r14_banks[0][i],
+            None => self.get_register(reg),
+        }
+    }
+
+    pub fn set_user_register(&mut self, reg: Register, val: u32) {
+        match self.user_bank_slot(reg) {
+            Some(UserBankSlot::R8R12(i)) => self.r8_r12_banks[0][i] = val,
+            Some(UserBankSlot::R13R14(i)) => self.r13_r14_banks[0][i] = val,
+            None => self.set_register(reg, val),
+        }
+    }
+
+    // Where the user copy of reg is held while it is banked out, if it is
+    fn user_bank_slot(&self, reg: Register) -> Option<UserBankSlot> {
+        let mode = self.mode();
+
+        match reg {
+            Register::R8 | Register::R9 | Register::R10 | Register::R11 | Register::R12
+                if mode == Mode::Fiq =>
+            {
+                Some(UserBankSlot::R8R12(reg as usize - Register::R8 as usize))
+            }
+            Register::R13 | Register::R14 if mode.bank() != Mode::User.bank() => {
+                Some(UserBankSlot::R13R14(reg as usize - Register::R13 as usize))
+            }
+            _ => None,
+        }
+    }
+
+    pub fn cpsr(&self) -> u32 {
+        self.cpsr
+    }
+
+    // Writing the mode bits switches the visible register bank
+    pub fn set_cpsr(&mut self, val: u32) {
+        let old_mode = self.mode();
+
+        let val = match Mode::from_bits(val) {
+            Some(new_mode) => {
+                self.switch_bank(old_mode, new_mode);
+                val
+            }
+            None => {
+                log::warn!("Ignoring write of invalid mode {:5b}", val & MODE_MASK);
+                (val & !MODE_MASK) | old_mode.bits()
+            }
+        };
+
+        self.cpsr = val;
+        self.state = if val & FLAG_T != 0 {
+            CpuState::Thumb
+        } else {
+            CpuState::Arm
+        };
+    }
+
+    pub fn mode(&self) -> Mode {
+        Mode::from_bits(self.cpsr).expect("cpsr holds a valid mode")
+    }
+
+    // User and System mode have no SPSR, reads give the CPSR
+    pub fn spsr(&self) -> u32 {
+        match self.mode() {
+            Mode::User | Mode::System => self.cpsr,
+            mode => self.spsr_banks[mode.bank()],
+        }
+    }
+
+    pub fn set_spsr(&mut self, val: u32) {
+        match self.mode() {
+            Mode::User | Mode::System => {
+                log::warn!("Ignoring SPSR write in {:?} mode", self.mode());
+            }
+            mode => self.spsr_banks[mode.bank()] = val,
+        }
+    }
+
+    fn switch_bank(&mut self, old_mode: Mode, new_mode: Mode) {
+        if old_mode.bank() == new_mode.bank() {
+            return;
+        }
+
+        self.r13_r14_banks[old_mode.bank()] = [self.r13, self.r14];
+        let [r13, r14] = self.r13_r14_banks[new_mode.bank()];
+        self.r13 = r13;
+        self.r14 = r14;
+
+        let old_fiq = old_mode == Mode::Fiq;
+        let new_fiq = new_mode == Mode::Fiq;
+
+        if old_fiq != new_fiq {
+            self.r8_r12_banks[old_fiq as usize] = [self.r8, self.r9, self.r10, self.r11, self.r12];
+            let [r8, r9, r10, r11, r12] = self.r8_r12_banks[new_fiq as usize];
+            self.r8 = r8;
+            self.r9 = r9;
+            self.r10 = r10;
+            self.r11 = r11;
+            self.r12 = r12;
+        }
+
+        log::debug!("Mode switch {:?} -> {:?}", old_mode, new_mode);
+    }
+
+    pub fn flag_n(&self) -> bool {
+        self.cpsr & FLAG_N != 0
+    }
+
+    pub fn flag_z(&self) -> bool {
+        self.cpsr & FLAG_Z != 0
+    }
+
+    pub fn flag_c(&self) -> bool {
+        self.cpsr & FLAG_C != 0
+    }
+
+    pub fn flag_v(&self) -> bool {
+        self.cpsr & FLAG_V != 0
+    }
+
+    pub fn set_flags(&mut self, n: bool, z: bool, c: bool, v: bool) {
+        let mut flags = 0;
+
+        if n {
+            flags |= FLAG_N;
+        }
+        if z {
+            flags |= FLAG_Z;
+        }
+        if c {
+            flags |= FLAG_C;
+        }
+        if v {
+            flags |= FLAG_V;
+        }
+
+        self.cpsr = (self.cpsr & !(FLAG_N | FLAG_Z | FLAG_C | FLAG_V)) | flags;
+    }
+}
+
+impl Default for Cpu {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 // To use the `{}` marker, the trait `fmt::Display` must be implemented
@@ -153,6 +608,496 @@ impl fmt::Display for Cpu {
         // stream: `f`. Returns `fmt::Result` which indicates whether the
         // operation succeeded or failed. Note that `write!` uses syntax which
         // is very similar to `println!`.
-        write!(f, "pc {:8x} cspr {:8x} lr {:8x} sp {:8x}", self.r15, self.cpsr, self.r14, self.r13)
+        write!(
+            f,
+            "pc {:8x} cspr {:8x} lr {:8x} sp {:8x}",
+            self.r15, self.cpsr, self.r14, self.r13
+        )
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::Bus;
+    use crate::interrupt::Interrupt;
+    use crate::memory::Memory;
+
+    const CONTROL_BITS: u32 = 0b11000000;
+
+    #[test]
+    fn test_irq_banks_sp_and_lr() {
+        let mut cpu = Cpu::new();
+        cpu.set_cpsr(CONTROL_BITS | Mode::User.bits());
+        cpu.r12 = 12;
+        cpu.r13 = 0x03007f00;
+        cpu.r14 = 0x08000200;
+
+        cpu.set_cpsr(CONTROL_BITS | Mode::Irq.bits());
+        assert_eq!(Mode::Irq, cpu.mode());
+        assert_eq!(12, cpu.r12);
+        assert_eq!(0, cpu.r13);
+
+        cpu.r13 = 0x03007fa0;
+        cpu.r14 = 0x08000300;
+
+        cpu.set_cpsr(CONTROL_BITS | Mode::System.bits());
+        assert_eq!(0x03007f00, cpu.r13);
+        assert_eq!(0x08000200, cpu.r14);
+
+        cpu.set_cpsr(CONTROL_BITS | Mode::Irq.bits());
+        assert_eq!(0x03007fa0, cpu.r13);
+        assert_eq!(0x08000300, cpu.r14);
+    }
+
+    #[test]
+    fn test_fiq_banks_r8_to_r14() {
+        let mut cpu = Cpu::new();
+        cpu.r8 = 8;
+        cpu.r12 = 12;
+        cpu.r13 = 13;
+
+        cpu.set_cpsr(CONTROL_BITS | Mode::Fiq.bits());
+        assert_eq!(0, cpu.r8);
+        assert_eq!(0, cpu.r12);
+        assert_eq!(0, cpu.r13);
+
+        cpu.r8 = 0x88;
+
+        // Moving to another non-user mode brings back the shared r8-r12
+        cpu.set_cpsr(CONTROL_BITS | Mode::Undefined.bits());
+        assert_eq!(8, cpu.r8);
+        assert_eq!(12, cpu.r12);
+        assert_eq!(0, cpu.r13);
+
+        cpu.set_cpsr(CONTROL_BITS | Mode::Fiq.bits());
+        assert_eq!(0x88, cpu.r8);
+
+        cpu.set_cpsr(CONTROL_BITS | Mode::Supervisor.bits());
+        assert_eq!(13, cpu.r13);
+    }
+
+    #[test]
+    fn test_spsr_per_mode() {
+        let mut cpu = Cpu::new();
+        cpu.set_spsr(0x1234);
+
+        cpu.set_cpsr(CONTROL_BITS | Mode::Abort.bits());
+        cpu.set_spsr(0x5678);
+
+        cpu.set_cpsr(CONTROL_BITS | Mode::Supervisor.bits());
+        assert_eq!(0x1234, cpu.spsr());
+
+        cpu.set_cpsr(CONTROL_BITS | Mode::User.bits());
+        cpu.set_spsr(0x9999);
+        assert_eq!(cpu.cpsr(), cpu.spsr());
+    }
+
+    #[test]
+    fn test_invalid_mode_ignored() {
+        let mut cpu = Cpu::new();
+
+        cpu.set_cpsr(0x10000000 | CONTROL_BITS);
+
+        assert_eq!(Mode::Supervisor, cpu.mode());
+        assert!(cpu.flag_v());
+    }
+
+    #[test]
+    fn test_thumb_fetches_halfwords() {
+        let mut cpu = Cpu::new();
+
+        // movs r0, #1; movs r1, #2; adds r2, r0, r1
+        let rom = vec![0x01, 0x20, 0x02, 0x21, 0x42, 0x18, 0x00, 0x00];
+        let mut mem = Memory::new_with_bios_and_rom(vec![0; 0x4000], rom);
+
+        cpu.set_state(CpuState::Thumb);
+        cpu.r15 = 0x08000000;
+
+        for _ in 0..5 {
+            cpu.cycle(&mut mem);
+        }
+
+        assert_eq!(1, cpu.r0);
+        assert_eq!(2, cpu.r1);
+        assert_eq!(3, cpu.r2);
+        assert_eq!(0x0800000a, cpu.r15);
+    }
+
+    #[test]
+    fn test_user_register_access() {
+        let mut cpu = Cpu::new();
+        cpu.set_cpsr(CONTROL_BITS | Mode::User.bits());
+        cpu.r8 = 8;
+        cpu.r13 = 13;
+
+        cpu.set_cpsr(CONTROL_BITS | Mode::Fiq.bits());
+        cpu.r8 = 0x88;
+        cpu.r13 = 0x1313;
+
+        assert_eq!(8, cpu.get_user_register(Register::R8));
+        assert_eq!(13, cpu.get_user_register(Register::R13));
+        assert_eq!(0x1313, cpu.get_register(Register::R13));
+
+        cpu.set_user_register(Register::R13, 0x33);
+        cpu.set_cpsr(CONTROL_BITS | Mode::User.bits());
+
+        assert_eq!(0x33, cpu.r13);
+        assert_eq!(8, cpu.r8);
+    }
+
+    #[test]
+    fn test_irq_entry_and_return() {
+        let mut cpu = Cpu::new();
+        cpu.set_cpsr(0x6000001f);
+
+        // About to execute the arm instruction at 0x08000100
+        cpu.r15 = 0x08000108;
+        cpu.enter_exception(Exception::Irq);
+
+        assert_eq!(Mode::Irq, cpu.mode());
+        assert_eq!(0x18, cpu.r15);
+        assert_eq!(0x08000104, cpu.r14);
+        assert_eq!(0x6000001f, cpu.spsr());
+        assert!(cpu.irq_disabled());
+        assert!(cpu.cpsr() & FLAG_F == 0);
+    }
+
+    #[test]
+    fn test_exception_from_thumb() {
+        let mut cpu = Cpu::new();
+        cpu.set_cpsr(0x10);
+        cpu.set_state(CpuState::Thumb);
+
+        // swi in the execute stage at 0x08000200
+        cpu.r15 = 0x08000204;
+        cpu.enter_exception(Exception::SoftwareInterrupt);
+
+        assert_eq!(CpuState::Arm, cpu.state());
+        assert_eq!(Mode::Supervisor, cpu.mode());
+        assert_eq!(0x08, cpu.r15);
+        assert_eq!(0x08000202, cpu.r14);
+        assert_eq!(0x30, cpu.spsr());
+
+        // And from thumb an IRQ handler's subs pc, lr, #4 returns to the interrupted instruction
+        cpu.set_cpsr(0x30);
+        cpu.r15 = 0x08000204;
+        cpu.enter_exception(Exception::Irq);
+
+        assert_eq!(0x08000204, cpu.r14);
+    }
+
+    #[test]
+    fn test_fiq_disables_fiq() {
+        let mut cpu = Cpu::new();
+        cpu.set_cpsr(0x1f);
+        cpu.r8 = 8;
+
+        cpu.enter_exception(Exception::Fiq);
+
+        assert_eq!(Mode::Fiq, cpu.mode());
+        assert!(cpu.cpsr() & FLAG_F != 0);
+        assert_eq!(0, cpu.r8);
+    }
+
+    // Plain little endian RAM from address 0 that records every opcode fetch
+    struct FlatBus {
+        ram: Vec<u8>,
+        fetches: Vec<(u32, Access)>,
+        cycles: u64, // One per word read
+    }
+
+    impl FlatBus {
+        fn with_program(program: &[u32]) -> FlatBus {
+            let mut ram = vec![0; 0x100];
+
+            for (i, op) in program.iter().enumerate() {
+                ram[i * 4..i * 4 + 4].copy_from_slice(&op.to_le_bytes());
+            }
+
+            FlatBus {
+                ram,
+                fetches: vec![],
+                cycles: 0,
+            }
+        }
+    }
+
+    impl Bus for FlatBus {
+        fn read_8(&mut self, addr: u32, _access: Access, _kind: AccessKind) -> u8 {
+            self.ram[addr as usize]
+        }
+
+        fn read_16(&mut self, addr: u32, access: Access, kind: AccessKind) -> u16 {
+            let addr = addr & !0b1;
+            u16::from_le_bytes([
+                self.read_8(addr, access, kind),
+                self.read_8(addr + 1, access, kind),
+            ])
+        }
+
+        fn read_32(&mut self, addr: u32, access: Access, kind: AccessKind) -> u32 {
+            if kind == AccessKind::Opcode {
+                self.fetches.push((addr, access));
+            }
+            self.cycles += 1;
+
+            let addr = addr & !0b11;
+            (self.read_16(addr + 2, access, AccessKind::Data) as u32) << 16
+                | self.read_16(addr, access, AccessKind::Data) as u32
+        }
+
+        fn write_8(&mut self, addr: u32, val: u8, _access: Access) {
+            self.ram[addr as usize] = val;
+        }
+
+        fn write_16(&mut self, addr: u32, val: u16, access: Access) {
+            let addr = addr & !0b1;
+            self.write_8(addr, val as u8, access);
+            self.write_8(addr + 1, (val >> 8) as u8, access);
+        }
+
+        fn write_32(&mut self, addr: u32, val: u32, access: Access) {
+            let addr = addr & !0b11;
+            self.write_16(addr, val as u16, access);
+            self.write_16(addr + 2, (val >> 16) as u16, access);
+        }
+
+        fn idle(&mut self, cycles: u32) {
+            self.cycles += cycles as u64;
+        }
+
+        fn cycles(&self) -> u64 {
+            self.cycles
+        }
+    }
+
+    impl System for FlatBus {}
+
+    #[test]
+    fn test_runs_on_any_bus() {
+        // mov r0, #0x40; mov r1, #5; str r1, [r0]; b .
+        let mut bus = FlatBus::with_program(&[0xe3a00040, 0xe3a01005, 0xe5801000, 0xeafffffe]);
+        let mut cpu = Cpu::new();
+
+        for _ in 0..7 {
+            cpu.cycle(&mut bus);
+        }
+
+        assert_eq!(5, bus.ram[0x40]);
+
+        // Fetches are sequential until the branch refills the pipeline
+        assert_eq!((0x0, Access::NonSequential), bus.fetches[0]);
+        assert_eq!((0x4, Access::Sequential), bus.fetches[1]);
+        assert_eq!((0xc, Access::NonSequential), bus.fetches[6]);
+    }
+
+    #[test]
+    fn test_cycle_counts_wait_states() {
+        // mov r0, #1; b .
+        let rom = [0xe3a00001u32, 0xeafffffe]
+            .iter()
+            .flat_map(|op| op.to_le_bytes().to_vec())
+            .collect();
+
+        let mut mem = Memory::new_with_bios_and_rom(vec![], rom);
+        let mut cpu = Cpu::new();
+        cpu.r15 = 0x08000000;
+
+        // A non-sequential arm fetch from ROM at the reset WAITCNT is 1+4 then 1+2
+        assert_eq!(8, cpu.cycle(&mut mem));
+        assert_eq!(6, cpu.cycle(&mut mem));
+
+        // Faster wait states apply straight away
+        mem.set_halfword(0x04000204, 0b0001_0100);
+        assert_eq!(4, cpu.cycle(&mut mem));
+        assert_eq!(8 + 6 + 4, mem.cycles());
+    }
+
+    fn arm_program(program: &[u32]) -> Vec<u8> {
+        program
+            .iter()
+            .flat_map(|op| op.to_le_bytes().to_vec())
+            .collect()
+    }
+
+    #[test]
+    fn test_irq_taken_between_instructions() {
+        // b .
+        let mut mem = Memory::new_with_bios_and_rom(vec![0; 0x4000], arm_program(&[0xeafffffe]));
+        let mut cpu = Cpu::new();
+        cpu.set_cpsr(0x1f);
+        cpu.r15 = 0x08000000;
+
+        mem.set_halfword(0x04000200, Interrupt::Timer0.bit());
+        mem.set_halfword(0x04000208, 1);
+
+        for _ in 0..4 {
+            cpu.cycle(&mut mem);
+        }
+        assert_eq!(Mode::System, cpu.mode());
+
+        mem.request_interrupt(Interrupt::Timer0);
+
+        // The line only rises after the delay, and the IRQ waits for a full pipeline
+        while cpu.mode() != Mode::Irq {
+            cpu.cycle(&mut mem);
+        }
+
+        assert_eq!(0x18, cpu.r15);
+        assert_eq!(0x08000004, cpu.r14);
+        assert!(cpu.irq_disabled());
+
+        // With CPSR.I set nothing more is taken
+        cpu.r15 = 0x08000000;
+        for _ in 0..8 {
+            cpu.cycle(&mut mem);
+        }
+        assert_eq!(0x08000008, cpu.r15);
+    }
+
+    #[test]
+    fn test_hle_bios_calls_irq_handler() {
+        // b .
+        let mut mem = Memory::new_with_rom(arm_program(&[0xeafffffe]));
+        let mut cpu = Cpu::new();
+        cpu.skip_bios();
+        cpu.set_hle_bios(true);
+
+        // mov r4, #0x42; add r0, r0, #0x200; mov r1, #1; strh r1, [r0, #2]; bx lr
+        let handler = [0xe3a04042, 0xe2800c02, 0xe3a01001, 0xe1c010b2, 0xe12fff1e];
+        for (i, op) in handler.iter().enumerate() {
+            mem.set_word(0x03000000 + i as u32 * 4, *op);
+        }
+        mem.set_word(0x03007ffc, 0x03000000);
+
+        mem.set_halfword(0x04000200, Interrupt::VBlank.bit());
+        mem.set_halfword(0x04000208, 1);
+        mem.request_interrupt(Interrupt::VBlank);
+
+        for _ in 0..100 {
+            cpu.cycle(&mut mem);
+        }
+
+        assert_eq!(0x42, cpu.r4);
+        assert_eq!(Mode::System, cpu.mode());
+        assert_eq!(0, mem.get_halfword(0x04000202));
+        assert!(cpu.r15 >= 0x08000000 && cpu.r15 < 0x08000010);
+    }
+
+    // Sets the BIOS flags VBlankIntrWait is waiting on and acknowledges vblank
+    fn install_vblank_handler(mem: &mut Memory) {
+        // mov r1, #1; strh r1, [r0, #-8] (BIOS flags); add r0, r0, #0x200; strh r1, [r0, #2]; bx lr
+        let handler = [0xe3a01001, 0xe14010b8, 0xe2800c02, 0xe1c010b2, 0xe12fff1e];
+        for (i, op) in handler.iter().enumerate() {
+            mem.set_word(0x03000000 + i as u32 * 4, *op);
+        }
+        mem.set_word(0x03007ffc, 0x03000000);
+    }
+
+    #[test]
+    fn test_halted_until_interrupt() {
+        // swi 0x05 (VBlankIntrWait); mov r5, #1; b .
+        let mut mem = Memory::new_with_rom(arm_program(&[0xef050000, 0xe3a05001, 0xeafffffe]));
+        let mut cpu = Cpu::new();
+        cpu.skip_bios();
+        cpu.set_hle_bios(true);
+
+        install_vblank_handler(&mut mem);
+        mem.set_halfword(0x04000200, Interrupt::VBlank.bit());
+
+        while !mem.halted() {
+            cpu.cycle(&mut mem);
+        }
+
+        // Asleep, the cpu skips straight to the next event, unless it's only running for less
+        let start = mem.cycles();
+        cpu.run(&mut mem, 100);
+        assert_eq!(start + 100, mem.cycles());
+
+        let r15 = cpu.r15;
+        let next_event = mem.next_event();
+        cpu.cycle(&mut mem);
+        assert_eq!(next_event, mem.cycles());
+        assert_eq!(r15, cpu.r15);
+        assert_eq!(0, cpu.r5);
+
+        mem.request_interrupt(Interrupt::VBlank);
+
+        for _ in 0..100 {
+            cpu.cycle(&mut mem);
+        }
+
+        assert!(!mem.halted());
+        assert_eq!(1, cpu.r5);
+        assert_eq!(Mode::System, cpu.mode());
+        assert_eq!(0, mem.get_halfword(0x03007ff8));
+    }
+
+    #[test]
+    fn test_run_wakes_on_vblank() {
+        // loop: swi 0x05 (VBlankIntrWait); add r5, r5, #1; b loop
+        let mut mem = Memory::new_with_rom(arm_program(&[0xef050000, 0xe2855001, 0xeafffffc]));
+        let mut cpu = Cpu::new();
+        cpu.skip_bios();
+        cpu.set_hle_bios(true);
+
+        install_vblank_handler(&mut mem);
+        mem.set_halfword(0x04000004, 1 << 3);
+        mem.set_halfword(0x04000200, Interrupt::VBlank.bit());
+
+        // Two frames see two vblanks, the first after 160 lines
+        cpu.run(&mut mem, 159 * 1232);
+        assert_eq!(0, cpu.r5);
+
+        cpu.run(&mut mem, 2 * 228 * 1232 - 159 * 1232);
+        assert_eq!(2, cpu.r5);
+        assert!(mem.halted());
+    }
+
+    // mov r0, #0x04000000; add r0, r0, #0x204; mov r1, #0x4300; orr r1, r1, #0x17; strh r1, [r0]
+    const ENABLE_PREFETCH: [u32; 5] = [
+        0xe3a00301, 0xe2800f81, 0xe3a01c43, 0xe3811017, 0xe1c010b0,
+    ];
+
+    #[test]
+    fn test_prefetch_enabled_from_iwram() {
+        // mov r2, #1; b .
+        let program = arm_program(&[0xe3a02001, 0xeafffffe]);
+        let mut mem = Memory::new_with_bios_and_rom(vec![0; 0x4000], program);
+        let mut cpu = Cpu::new();
+        cpu.set_cpsr(0x1f);
+        cpu.r15 = 0x03000000;
+
+        // ... then mov pc, #0x08000000
+        for (i, op) in ENABLE_PREFETCH.iter().chain(&[0xe3a0f302]).enumerate() {
+            mem.set_word(0x03000000 + i as u32 * 4, *op);
+        }
+
+        for _ in 0..40 {
+            cpu.cycle(&mut mem);
+        }
+
+        assert_eq!(0x4317, mem.get_halfword(0x04000204));
+        assert_eq!(1, cpu.r2);
+    }
+
+    #[test]
+    fn test_prefetch_enabled_from_rom() {
+        // ... then mov r2, #1; b .
+        let mut program = ENABLE_PREFETCH.to_vec();
+        program.extend(&[0xe3a02001, 0xeafffffe]);
+
+        let mut mem = Memory::new_with_bios_and_rom(vec![0; 0x4000], arm_program(&program));
+        let mut cpu = Cpu::new();
+        cpu.set_cpsr(0x1f);
+        cpu.r15 = 0x08000000;
+
+        for _ in 0..40 {
+            cpu.cycle(&mut mem);
+        }
+
+        assert_eq!(0x4317, mem.get_halfword(0x04000204));
+        assert_eq!(1, cpu.r2);
+    }
+}
@@ -0,0 +1,192 @@
+// DMAxCNT_H
+const DMA_REPEAT: u16 = 1 << 9;
+const DMA_WORD: u16 = 1 << 10;
+const DMA_IRQ: u16 = 1 << 14;
+const DMA_ENABLE: u16 = 1 << 15;
+
+// Address masks per channel, and the most units a transfer can move, which a count of 0 means
+const SRC_MASKS: [u32; 4] = [0x07FFFFFF, 0x0FFFFFFF, 0x0FFFFFFF, 0x0FFFFFFF];
+const DST_MASKS: [u32; 4] = [0x07FFFFFF, 0x07FFFFFF, 0x07FFFFFF, 0x0FFFFFFF];
+const MAX_UNITS: [u32; 4] = [0x4000, 0x4000, 0x4000, 0x10000];
+
+// When an enabled channel transfers
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Timing {
+    Immediate,
+    VBlank,
+    HBlank,
+    Special, // Sound FIFO refills on channels 1 and 2, video capture on 3
+}
+
+// One of the four DMA channels. The addresses and count are latched from the registers when
+// the channel is turned on, and carry on from where they got to between repeats
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Dma {
+    control: u16,
+    src: u32,
+    dst: u32,
+    units: u32,
+}
+
+impl Dma {
+    pub fn enabled(&self) -> bool {
+        self.control & DMA_ENABLE != 0
+    }
+
+    pub fn irq_enabled(&self) -> bool {
+        self.control & DMA_IRQ != 0
+    }
+
+    pub fn timing(&self) -> Timing {
+        match (self.control >> 12) & 0b11 {
+            0 => Timing::Immediate,
+            1 => Timing::VBlank,
+            2 => Timing::HBlank,
+            _ => Timing::Special,
+        }
+    }
+
+    pub fn dst(&self) -> u32 {
+        self.dst
+    }
+
+    fn units(n: usize, count: u16) -> u32 {
+        match count as u32 & (MAX_UNITS[n] - 1) {
+            0 => MAX_UNITS[n],
+            units => units,
+        }
+    }
+
+    // Applies a DMAxCNT_H write, returning true if it turned the channel on
+    pub fn set_control(&mut self, n: usize, control: u16, src: u32, dst: u32, count: u16) -> bool {
+        let starting = !self.enabled() && control & DMA_ENABLE != 0;
+
+        self.control = control;
+
+        if starting {
+            self.src = src & SRC_MASKS[n];
+            self.dst = dst & DST_MASKS[n];
+            self.units = Dma::units(n, count);
+        }
+
+        starting
+    }
+
+    // The source and destination of each unit the transfer moves, and whether they're words.
+    // Sound FIFO refills are always 4 words to the same address
+    pub fn transfer(&mut self, fifo: bool) -> (Vec<(u32, u32)>, bool) {
+        let word = fifo || self.control & DMA_WORD != 0;
+        let size = if word { 4 } else { 2 };
+        let units = if fifo { 4 } else { self.units };
+
+        let step = |adjust: u16| match adjust & 0b11 {
+            1 => -size,
+            2 => 0,
+            _ => size,
+        };
+        let src_step = step(self.control >> 7);
+        let dst_step = if fifo { 0 } else { step(self.control >> 5) };
+
+        let offset = |step: i32, i: u32| (step * i as i32) as u32;
+        let addresses = (0..units)
+            .map(|i| {
+                (
+                    self.src.wrapping_add(offset(src_step, i)),
+                    self.dst.wrapping_add(offset(dst_step, i)),
+                )
+            })
+            .collect();
+
+        self.src = self.src.wrapping_add(offset(src_step, units));
+        self.dst = self.dst.wrapping_add(offset(dst_step, units));
+
+        (addresses, word)
+    }
+
+    // Repeating channels stay on for their next trigger, reloading the count and, if asked, the
+    // destination. Anything else turns itself off, which is returned as false
+    pub fn finish(&mut self, n: usize, dst: u32, count: u16) -> bool {
+        if self.control & DMA_REPEAT == 0 || self.timing() == Timing::Immediate {
+            self.control &= !DMA_ENABLE;
+            return false;
+        }
+
+        self.units = Dma::units(n, count);
+
+        if (self.control >> 5) & 0b11 == 0b11 {
+            self.dst = dst & DST_MASKS[n];
+        }
+
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_transfer_addresses() {
+        let mut dma = Dma::default();
+
+        // Halfwords, source decrementing, destination fixed
+        assert!(dma.set_control(
+            3,
+            DMA_ENABLE | (0b01 << 7) | (0b10 << 5),
+            0x02000010,
+            0x03000000,
+            3
+        ));
+        let (units, word) = dma.transfer(false);
+
+        assert!(!word);
+        assert_eq!(
+            vec![
+                (0x02000010, 0x03000000),
+                (0x0200000E, 0x03000000),
+                (0x0200000C, 0x03000000)
+            ],
+            units
+        );
+        assert!(!dma.finish(3, 0x03000000, 3));
+        assert!(!dma.enabled());
+    }
+
+    #[test]
+    fn test_repeat_reloads_destination() {
+        let mut dma = Dma::default();
+
+        // HBlank, repeating, words, destination incrementing and reloaded
+        let control = DMA_ENABLE | (0b10 << 12) | DMA_REPEAT | DMA_WORD | (0b11 << 5);
+        dma.set_control(0, control, 0x02000000, 0x06000000, 0);
+
+        let (units, _) = dma.transfer(false);
+        assert_eq!(0x4000, units.len());
+        assert_eq!((0x0200FFFC, 0x0600FFFC), units[0x3FFF]);
+
+        assert!(dma.finish(0, 0x06000000, 2));
+        let (units, _) = dma.transfer(false);
+        assert_eq!(
+            vec![(0x02010000, 0x06000000), (0x02010004, 0x06000004)],
+            units
+        );
+    }
+
+    #[test]
+    fn test_fifo_transfer() {
+        let mut dma = Dma::default();
+
+        dma.set_control(
+            1,
+            DMA_ENABLE | DMA_REPEAT | (0b11 << 12),
+            0x02000000,
+            0x040000A0,
+            1,
+        );
+        let (units, word) = dma.transfer(true);
+
+        assert!(word);
+        assert_eq!((0x0200000C, 0x040000A0), units[3]);
+        assert!(dma.finish(1, 0x040000A0, 1));
+    }
+}
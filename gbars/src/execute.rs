@@ -1,5 +1,10 @@
-use crate::cpu::Cpu;
-use crate::instruction::{Branch, Instruction, InstructionOp};
+use crate::bios;
+use crate::bus::{Access, AccessKind, Bus};
+use crate::cpu::{Cpu, CpuState, Exception, Mode, Register, FLAG_T};
+use crate::instruction::{
+    Branch, Condition, DataProcessingOpCode, Instruction, InstructionOp, Offset, Operand,
+};
+use crate::shifter::{self, Shift};
 
 fn sign_extend_24(num: u32) -> i32 {
     let sign_extend = num & (0b1 << 23) != 0;
@@ -11,21 +16,185 @@ fn sign_extend_24(num: u32) -> i32 {
     }
 }
 
-use log;
+// Returns the internal cycles taken. Memory accesses are timed by the bus as they're made
+pub fn execute<B: Bus>(cpu: &mut Cpu, bus: &mut B, instr: Instruction) -> u32 {
+    if !condition_passed(cpu, &instr.condition) {
+        log::info!("Condition {:?} failed", instr.condition);
+
+        // A skipped instruction only costs its fetch
+        return 0;
+    }
 
-pub fn execute(cpu: &mut Cpu, instr: Instruction) {
-    // TODO Handle instr.condition
     match instr.instruction {
-        InstructionOp::Branch { branch } => {
-            execute_branch(cpu, branch);
+        InstructionOp::Branch { branch } => execute_branch(cpu, branch),
+        InstructionOp::DataProcessing {
+            opcode,
+            dest,
+            operand1,
+            operand2,
+            alter_condition,
+        } => execute_data_processing(cpu, opcode, dest, operand1, operand2, alter_condition),
+        InstructionOp::HalfwordDataTransfer {
+            base,
+            source_dest,
+            load,
+            write_back,
+            signed,
+            halfword,
+            add_offset,
+            pre_index,
+            offset,
+        } => execute_halfword_data_transfer(
+            cpu,
+            bus,
+            base,
+            source_dest,
+            load,
+            write_back,
+            signed,
+            halfword,
+            add_offset,
+            pre_index,
+            offset,
+        ),
+        InstructionOp::SingleDataTransfer {
+            base,
+            source_dest,
+            load,
+            write_back,
+            write_byte,
+            add_offset,
+            pre_index,
+            offset,
+        } => execute_single_data_transfer(
+            cpu,
+            bus,
+            base,
+            source_dest,
+            load,
+            write_back,
+            write_byte,
+            add_offset,
+            pre_index,
+            offset,
+        ),
+        InstructionOp::BlockDataTransfer {
+            base,
+            load,
+            write_back,
+            force_psr,
+            add_offset,
+            pre_index,
+            register_list,
+        } => execute_block_data_transfer(
+            cpu,
+            bus,
+            base,
+            load,
+            write_back,
+            force_psr,
+            add_offset,
+            pre_index,
+            register_list,
+        ),
+        InstructionOp::Multiply {
+            dest,
+            operand1,
+            operand2,
+            accumulate,
+            acc_operand,
+            alter_condition,
+        } => execute_multiply(
+            cpu,
+            dest,
+            operand1,
+            operand2,
+            accumulate,
+            acc_operand,
+            alter_condition,
+        ),
+        InstructionOp::MultiplyLong {
+            dest_high,
+            dest_low,
+            operand1,
+            operand2,
+            accumulate,
+            signed,
+            alter_condition,
+        } => execute_multiply_long(
+            cpu,
+            dest_high,
+            dest_low,
+            operand1,
+            operand2,
+            accumulate,
+            signed,
+            alter_condition,
+        ),
+        InstructionOp::Swap {
+            source,
+            dest,
+            base,
+            byte,
+        } => execute_swap(cpu, bus, source, dest, base, byte),
+        InstructionOp::MoveFromPsr { dest, spsr } => execute_move_from_psr(cpu, dest, spsr),
+        InstructionOp::MoveToPsr {
+            source,
+            spsr,
+            fields,
+        } => execute_move_to_psr(cpu, source, spsr, fields),
+        InstructionOp::SoftwareInterrupt { comment } => {
+            log::info!("Software interrupt {:x}", comment);
+
+            // The BIOS function number is the top byte of an arm comment
+            let number = match cpu.state() {
+                CpuState::Arm => (comment >> 16) as u8,
+                CpuState::Thumb => comment as u8,
+            };
+
+            if cpu.has_hle_bios() {
+                return bios::software_interrupt(cpu, bus, number);
+            }
+
+            cpu.enter_exception(Exception::SoftwareInterrupt);
+
+            0
         }
-        _ => {
-            log::info!("");
+        InstructionOp::Undefined => {
+            cpu.enter_exception(Exception::Undefined);
+
+            1
         }
     }
 }
 
-fn execute_branch(cpu: &mut Cpu, branch: Branch) {
+fn condition_passed(cpu: &Cpu, condition: &Condition) -> bool {
+    let n = cpu.flag_n();
+    let z = cpu.flag_z();
+    let c = cpu.flag_c();
+    let v = cpu.flag_v();
+
+    match condition {
+        Condition::Equal => z,
+        Condition::NotEqual => !z,
+        Condition::UnsignedGe => c,
+        Condition::UnsignedLt => !c,
+        Condition::Negative => n,
+        Condition::Positive => !n,
+        Condition::Oveflow => v,
+        Condition::NoOverflow => !v,
+        Condition::UnsignedGt => c && !z,
+        Condition::UnsignedLe => !c || z,
+        Condition::Ge => n == v,
+        Condition::Lt => n != v,
+        Condition::Gt => !z && n == v,
+        Condition::Le => z || n != v,
+        Condition::Always => true,
+        Condition::Never => false,
+    }
+}
+
+fn execute_branch(cpu: &mut Cpu, branch: Branch) -> u32 {
     match branch {
         Branch::Offset { offset, link } => {
             // offset is a 24bit signed two's complement number
@@ -33,10 +202,14 @@ fn execute_branch(cpu: &mut Cpu, branch: Branch) {
             let signed_extended = sign_extend_24(offset);
             let backwards = offset & (0b1 << 23) != 0;
 
-            let newpc = signed_extended << 2;
+            // Offset is in instructions, thumb branches are halfword aligned
+            let newpc = match cpu.state() {
+                CpuState::Arm => signed_extended << 2,
+                CpuState::Thumb => signed_extended << 1,
+            };
 
             if link {
-                // TODO Need to know if we're thumb or not
+                // Only arm branches link, thumb uses the LongLink pair
                 cpu.r14 = cpu.r15 - 4;
             }
 
@@ -48,27 +221,597 @@ fn execute_branch(cpu: &mut Cpu, branch: Branch) {
 
             log::info!("Branch to {:8x}", newpc);
 
-            cpu.r15 = newpc;
-
-            cpu.flush_pipeline();
+            cpu.branch_to(newpc);
         }
         Branch::Exchange { register } => {
             let val: u32 = cpu.get_register(register);
 
             let thumb = val & 0b1 != 0;
 
-            cpu.r15 = val & (!0b1);
-            
-            log::info!("Branch ({}) to {:8x}", if thumb {"Thumb"} else {"Arm"}, cpu.r15);
-            
-            cpu.flush_pipeline();
+            let state = if thumb {
+                CpuState::Thumb
+            } else {
+                CpuState::Arm
+            };
+
+            cpu.set_state(state);
+            cpu.branch_to(val);
+
+            log::info!("Branch ({:?}) to {:8x}", state, cpu.r15);
+        }
+        Branch::LongLinkHigh { offset } => {
+            // First half of BL puts pc plus the sign extended upper offset in lr
+            let upper = (((offset << 21) as i32) >> 9) as u32;
+
+            cpu.r14 = cpu.r15.wrapping_add(upper);
+
+            return 0;
+        }
+        Branch::LongLinkLow { offset } => {
+            // Second half jumps and leaves the return address in lr, with bit 0 set for thumb
+            let next_instr = cpu.r15 - 2;
+            let newpc = cpu.r14.wrapping_add(offset << 1);
+
+            cpu.r14 = next_instr | 0b1;
+
+            log::info!("Branch with link to {:8x}", newpc);
+
+            cpu.branch_to(newpc);
+        }
+    }
+
+    0
+}
+
+fn execute_data_processing(
+    cpu: &mut Cpu,
+    opcode: DataProcessingOpCode,
+    dest: Register,
+    operand1: Register,
+    operand2: Operand,
+    alter_condition: bool,
+) -> u32 {
+    let register_shift = is_register_shift(&operand2);
+
+    let mut op1 = read_register_for_shift(cpu, operand1, register_shift);
+
+    if cpu.state() == CpuState::Thumb
+        && operand1 == Register::R15
+        && matches!(operand2, Operand::Immediate { .. })
+    {
+        // Thumb ADD Rd, PC, #Imm sees the pc with bit 1 cleared
+        op1 &= !0b10;
+    }
+    let (op2, shifter_carry) = read_operand2(cpu, operand2);
+    let carry = cpu.flag_c();
+    let overflow = cpu.flag_v();
+
+    // (result, carry, overflow). Logical ops take carry from the shifter and leave V alone
+    let (result, carry, overflow) = match opcode {
+        DataProcessingOpCode::And | DataProcessingOpCode::Tst => {
+            (op1 & op2, shifter_carry, overflow)
+        }
+        DataProcessingOpCode::Eor | DataProcessingOpCode::Teq => {
+            (op1 ^ op2, shifter_carry, overflow)
+        }
+        DataProcessingOpCode::Orr => (op1 | op2, shifter_carry, overflow),
+        DataProcessingOpCode::Mov => (op2, shifter_carry, overflow),
+        DataProcessingOpCode::Bic => (op1 & !op2, shifter_carry, overflow),
+        DataProcessingOpCode::Mvn => (!op2, shifter_carry, overflow),
+        DataProcessingOpCode::Sub | DataProcessingOpCode::Cmp => sub_with_carry(op1, op2, true),
+        DataProcessingOpCode::Rsb => sub_with_carry(op2, op1, true),
+        DataProcessingOpCode::Add | DataProcessingOpCode::Cmn => add_with_carry(op1, op2, false),
+        DataProcessingOpCode::Adc => add_with_carry(op1, op2, carry),
+        DataProcessingOpCode::Sbc => sub_with_carry(op1, op2, carry),
+        DataProcessingOpCode::Rsc => sub_with_carry(op2, op1, carry),
+    };
+
+    let writes_result = !matches!(
+        opcode,
+        DataProcessingOpCode::Tst
+            | DataProcessingOpCode::Teq
+            | DataProcessingOpCode::Cmp
+            | DataProcessingOpCode::Cmn
+    );
+
+    if alter_condition {
+        // Thumb's hi register CMP PC, Rs also gets here, but only sets the flags
+        if dest == Register::R15 && cpu.state() == CpuState::Arm {
+            // S bit with Rd=R15 returns from an exception: restore the saved status
+            cpu.set_cpsr(cpu.spsr());
+        } else {
+            cpu.set_flags(result & (0b1 << 31) != 0, result == 0, carry, overflow);
         }
     }
+
+    if writes_result {
+        if dest == Register::R15 {
+            log::info!("Data processing write to pc {:8x}", result);
+            cpu.branch_to(result);
+        } else {
+            cpu.set_register(dest, result);
+        }
+    }
+
+    // A register specified shift costs an extra internal cycle
+    register_shift as u32
+}
+
+fn execute_multiply(
+    cpu: &mut Cpu,
+    dest: Register,
+    operand1: Register,
+    operand2: Register,
+    accumulate: bool,
+    acc_operand: Register,
+    alter_condition: bool,
+) -> u32 {
+    let rm = cpu.get_register(operand1);
+    let rs = cpu.get_register(operand2);
+
+    let mut result = rm.wrapping_mul(rs);
+
+    if accumulate {
+        result = result.wrapping_add(cpu.get_register(acc_operand));
+    }
+
+    cpu.set_register(dest, result);
+
+    if alter_condition {
+        // The ARM7TDMI leaves an internal value of its multiplier array in C that software
+        // can't make use of, so C is kept as it was. V is unaffected
+        cpu.set_flags(
+            result & (0b1 << 31) != 0,
+            result == 0,
+            cpu.flag_c(),
+            cpu.flag_v(),
+        );
+    }
+
+    multiplier_cycles(rs, true) + accumulate as u32
+}
+
+#[allow(clippy::too_many_arguments)]
+fn execute_multiply_long(
+    cpu: &mut Cpu,
+    dest_high: Register,
+    dest_low: Register,
+    operand1: Register,
+    operand2: Register,
+    accumulate: bool,
+    signed: bool,
+    alter_condition: bool,
+) -> u32 {
+    let rm = cpu.get_register(operand1);
+    let rs = cpu.get_register(operand2);
+
+    let mut result = if signed {
+        (rm as i32 as i64).wrapping_mul(rs as i32 as i64) as u64
+    } else {
+        (rm as u64) * (rs as u64)
+    };
+
+    if accumulate {
+        let acc = ((cpu.get_register(dest_high) as u64) << 32) | cpu.get_register(dest_low) as u64;
+        result = result.wrapping_add(acc);
+    }
+
+    cpu.set_register(dest_low, result as u32);
+    cpu.set_register(dest_high, (result >> 32) as u32);
+
+    if alter_condition {
+        cpu.set_flags(
+            result & (0b1 << 63) != 0,
+            result == 0,
+            cpu.flag_c(),
+            cpu.flag_v(),
+        );
+    }
+
+    multiplier_cycles(rs, signed) + 1 + accumulate as u32
+}
+
+// The multiplier terminates early once the remaining bits of the multiplier operand are all
+// zero (or, for signed multiplies, all one). Returns the internal cycles taken, 1 to 4
+fn multiplier_cycles(multiplier: u32, signed: bool) -> u32 {
+    let terminates = |mask: u32| {
+        let upper = multiplier & mask;
+        upper == 0 || (signed && upper == mask)
+    };
+
+    if terminates(0xffffff00) {
+        1
+    } else if terminates(0xffff0000) {
+        2
+    } else if terminates(0xff000000) {
+        3
+    } else {
+        4
+    }
+}
+
+fn execute_swap<B: Bus>(
+    cpu: &mut Cpu,
+    bus: &mut B,
+    source: Register,
+    dest: Register,
+    base: Register,
+    byte: bool,
+) -> u32 {
+    let addr = cpu.get_register(base);
+    let val = cpu.get_register(source);
+
+    let old = if byte {
+        bus.swap_8(addr, val as u8) as u32
+    } else {
+        bus.swap_32(addr, val)
+    };
+
+    log::info!("Swap {:8x} with {:8x} at {:8x}", val, old, addr);
+
+    cpu.set_register(dest, old);
+
+    1
+}
+
+#[allow(clippy::too_many_arguments)]
+fn execute_single_data_transfer<B: Bus>(
+    cpu: &mut Cpu,
+    bus: &mut B,
+    base: Register,
+    source_dest: Register,
+    load: bool,
+    write_back: bool,
+    write_byte: bool,
+    add_offset: bool,
+    pre_index: bool,
+    offset: Offset,
+) -> u32 {
+    let offset = match offset {
+        Offset::Immediate { offset } => offset as u32,
+        Offset::Register { shift, register } => read_shifted_register(cpu, shift, register).0,
+    };
+
+    let mut base_val = cpu.get_register(base);
+
+    if cpu.state() == CpuState::Thumb && base == Register::R15 {
+        // Thumb LDR Rd, [PC, #Imm] sees the pc with bit 1 cleared
+        base_val &= !0b10;
+    }
+
+    let (addr, written_back) = transfer_address(base_val, offset, add_offset, pre_index);
+
+    // Stores see the base before write back
+    let store_val = read_register_for_store(cpu, source_dest);
+
+    // Post-indexed transfers always write back
+    if write_back || !pre_index {
+        cpu.set_register(base, written_back);
+    }
+
+    if !load {
+        if write_byte {
+            log::info!("Store byte {:2x} at {:8x}", store_val as u8, addr);
+            bus.write_8(addr, store_val as u8, Access::NonSequential);
+        } else {
+            log::info!("Store word {:8x} at {:8x}", store_val, addr);
+            bus.write_32(addr, store_val, Access::NonSequential);
+        }
+
+        return 0;
+    }
+
+    let val = if write_byte {
+        bus.read_8(addr, Access::NonSequential, AccessKind::Data) as u32
+    } else {
+        // A misaligned word load reads the aligned word rotated so the addressed byte is lowest
+        bus.read_32(addr, Access::NonSequential, AccessKind::Data)
+            .rotate_right(8 * (addr & 0b11))
+    };
+
+    log::info!("Load {:8x} from {:8x}", val, addr);
+
+    if source_dest == Register::R15 {
+        cpu.branch_to(val);
+    } else {
+        cpu.set_register(source_dest, val);
+    }
+
+    // Loads end with an internal cycle to write the register
+    1
+}
+
+#[allow(clippy::too_many_arguments)]
+fn execute_halfword_data_transfer<B: Bus>(
+    cpu: &mut Cpu,
+    bus: &mut B,
+    base: Register,
+    source_dest: Register,
+    load: bool,
+    write_back: bool,
+    signed: bool,
+    halfword: bool,
+    add_offset: bool,
+    pre_index: bool,
+    offset: Offset,
+) -> u32 {
+    let offset = match offset {
+        Offset::Immediate { offset } => offset as u32,
+        Offset::Register { register, .. } => cpu.get_register(register),
+    };
+
+    let (addr, written_back) =
+        transfer_address(cpu.get_register(base), offset, add_offset, pre_index);
+
+    // Stores see the base before write back
+    let store_val = read_register_for_store(cpu, source_dest);
+
+    // Post-indexed transfers always write back
+    if write_back || !pre_index {
+        cpu.set_register(base, written_back);
+    }
+
+    if !load {
+        let val = store_val;
+
+        log::info!("Store halfword {:4x} at {:8x}", val as u16, addr);
+
+        bus.write_16(addr, val as u16, Access::NonSequential);
+
+        return 0;
+    }
+
+    let misaligned = addr & 0b1 != 0;
+    let (access, kind) = (Access::NonSequential, AccessKind::Data);
+
+    let val = match (signed, halfword) {
+        // A misaligned halfword load reads the aligned halfword rotated by a byte
+        (false, _) => (bus.read_16(addr, access, kind) as u32).rotate_right(8 * misaligned as u32),
+        (true, false) => bus.read_8(addr, access, kind) as i8 as u32,
+        // A misaligned signed halfword load sign extends the addressed byte instead
+        (true, true) if misaligned => bus.read_8(addr, access, kind) as i8 as u32,
+        (true, true) => bus.read_16(addr, access, kind) as i16 as u32,
+    };
+
+    log::info!("Load halfword {:8x} from {:8x}", val, addr);
+
+    if source_dest == Register::R15 {
+        cpu.branch_to(val);
+    } else {
+        cpu.set_register(source_dest, val);
+    }
+
+    // Loads end with an internal cycle to write the register
+    1
+}
+
+#[allow(clippy::too_many_arguments)]
+fn execute_block_data_transfer<B: Bus>(
+    cpu: &mut Cpu,
+    bus: &mut B,
+    base: Register,
+    load: bool,
+    write_back: bool,
+    force_psr: bool,
+    add_offset: bool,
+    pre_index: bool,
+    register_list: Vec<Register>,
+) -> u32 {
+    // An empty list transfers just the pc, but moves the base as if all 16 registers were
+    let (register_list, transfer_size) = if register_list.is_empty() {
+        (vec![Register::R15], 0x40)
+    } else {
+        let size = register_list.len() as u32 * 4;
+        (register_list, size)
+    };
+
+    let base_val = cpu.get_register(base);
+
+    // Registers are always transferred lowest first at the lowest address,
+    // so decrementing modes start from the bottom of the block
+    let (start, written_back) = if add_offset {
+        let start = if pre_index { base_val + 4 } else { base_val };
+        (start, base_val.wrapping_add(transfer_size))
+    } else {
+        let bottom = base_val.wrapping_sub(transfer_size);
+        let start = if pre_index { bottom } else { bottom + 4 };
+        (start, bottom)
+    };
+
+    let loads_pc = load && register_list.contains(&Register::R15);
+
+    // With the S bit set, anything but a load including the pc transfers User mode registers
+    let user_bank = force_psr && !loads_pc;
+
+    let mut addr = start;
+
+    // Only the first transfer of the block is non-sequential
+    let mut access = Access::NonSequential;
+
+    if load {
+        // A loaded base overwrites the written back value
+        if write_back {
+            cpu.set_register(base, written_back);
+        }
+
+        for reg in register_list {
+            let val = bus.read_32(addr, access, AccessKind::Data);
+
+            log::info!("Load multiple {:?} = {:8x} from {:8x}", reg, val, addr);
+
+            if reg == Register::R15 {
+                if force_psr {
+                    // LDM with the S bit and pc returns from an exception
+                    cpu.set_cpsr(cpu.spsr());
+                }
+
+                cpu.branch_to(val);
+            } else if user_bank {
+                cpu.set_user_register(reg, val);
+            } else {
+                cpu.set_register(reg, val);
+            }
+
+            addr = addr.wrapping_add(4);
+            access = Access::Sequential;
+        }
+
+        // Loads end with an internal cycle to write the last register
+        return 1;
+    }
+
+    for (i, reg) in register_list.into_iter().enumerate() {
+        let val = if user_bank {
+            cpu.get_user_register(reg)
+        } else {
+            read_register_for_store(cpu, reg)
+        };
+
+        log::info!("Store multiple {:?} = {:8x} at {:8x}", reg, val, addr);
+
+        bus.write_32(addr, val, access);
+
+        // Write back happens after the first store, so a base later in the list stores
+        // the new value
+        if i == 0 && write_back {
+            cpu.set_register(base, written_back);
+        }
+
+        addr = addr.wrapping_add(4);
+        access = Access::Sequential;
+    }
+
+    0
+}
+
+// Returns the address to transfer and the new base value for write back
+fn transfer_address(base: u32, offset: u32, add_offset: bool, pre_index: bool) -> (u32, u32) {
+    let offset_base = if add_offset {
+        base.wrapping_add(offset)
+    } else {
+        base.wrapping_sub(offset)
+    };
+
+    if pre_index {
+        (offset_base, offset_base)
+    } else {
+        (base, offset_base)
+    }
+}
+
+// The pc is stored a further instruction ahead
+fn read_register_for_store(cpu: &Cpu, register: Register) -> u32 {
+    let val = cpu.get_register(register);
+
+    if register == Register::R15 {
+        val + cpu.instruction_size()
+    } else {
+        val
+    }
+}
+
+fn execute_move_from_psr(cpu: &mut Cpu, dest: Register, spsr: bool) -> u32 {
+    let val = if spsr { cpu.spsr() } else { cpu.cpsr() };
+
+    cpu.set_register(dest, val);
+
+    0
+}
+
+fn execute_move_to_psr(cpu: &mut Cpu, source: Operand, spsr: bool, fields: u8) -> u32 {
+    let (val, _) = read_operand2(cpu, source);
+
+    let mut mask = (0..4)
+        .filter(|field| fields & (0b1 << field) != 0)
+        .fold(0u32, |mask, field| mask | (0xff << (field * 8)));
+
+    if spsr {
+        cpu.set_spsr((cpu.spsr() & !mask) | (val & mask));
+    } else {
+        // User mode can only change the condition flags
+        if cpu.mode() == Mode::User {
+            mask &= 0xff000000;
+        }
+
+        // Changing state through MSR isn't supported by the ARM7TDMI, only BX switches
+        mask &= !FLAG_T;
+
+        log::info!("MSR cpsr {:8x} mask {:8x}", val, mask);
+
+        cpu.set_cpsr((cpu.cpsr() & !mask) | (val & mask));
+    }
+
+    0
+}
+
+// Returns the value of operand2 and the carry out of the shifter
+fn read_operand2(cpu: &Cpu, operand: Operand) -> (u32, bool) {
+    match operand {
+        Operand::Immediate { rotate, value } => {
+            shifter::rotate_immediate(value, rotate, cpu.flag_c())
+        }
+        Operand::Register { shift, register } => read_shifted_register(cpu, shift, register),
+    }
+}
+
+// Applies the barrel shifter to a register operand.
+// Returns the shifted value and the carry out of the shifter
+fn read_shifted_register(cpu: &Cpu, shift: u8, register: Register) -> (u32, bool) {
+    match Shift::decode(shift) {
+        Shift::Immediate { shift_type, amount } => {
+            shifter::shift_immediate(shift_type, cpu.get_register(register), amount, cpu.flag_c())
+        }
+        Shift::Register {
+            shift_type,
+            register: amount_register,
+        } => {
+            let value = read_register_for_shift(cpu, register, true);
+            let amount = cpu.get_register(amount_register) as u8;
+
+            shifter::shift_register(shift_type, value, amount, cpu.flag_c())
+        }
+    }
+}
+
+fn is_register_shift(operand: &Operand) -> bool {
+    match operand {
+        Operand::Register { shift, .. } => matches!(Shift::decode(*shift), Shift::Register { .. }),
+        Operand::Immediate { .. } => false,
+    }
+}
+
+// The extra cycle taken by a register specified shift means the pc has advanced
+// another instruction by the time the operands are read
+fn read_register_for_shift(cpu: &Cpu, register: Register, register_shift: bool) -> u32 {
+    let val = cpu.get_register(register);
+
+    if register_shift && register == Register::R15 {
+        val + 4
+    } else {
+        val
+    }
+}
+
+// Returns (result, carry, overflow) of a + b + carry
+fn add_with_carry(a: u32, b: u32, carry: bool) -> (u32, bool, bool) {
+    let wide = a as u64 + b as u64 + carry as u64;
+    let result = wide as u32;
+
+    let carry_out = wide > u32::MAX as u64;
+    let overflow = (!(a ^ b) & (a ^ result)) & (0b1 << 31) != 0;
+
+    (result, carry_out, overflow)
+}
+
+// ARM subtraction is addition of the inverted operand. Carry is set when no borrow occurs
+fn sub_with_carry(a: u32, b: u32, carry: bool) -> (u32, bool, bool) {
+    add_with_carry(a, !b, carry)
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::memory::Memory;
 
     #[test]
     fn test_sign_extend() {
@@ -83,4 +826,875 @@ mod test {
             sign_extend_24(extend)
         );
     }
+
+    fn test_memory() -> Memory {
+        Memory::new_with_bios_and_rom(vec![0; 0x4000], vec![])
+    }
+
+    fn execute_arm(cpu: &mut Cpu, op: u32) -> u32 {
+        execute_arm_with_memory(cpu, &mut test_memory(), op)
+    }
+
+    fn execute_arm_with_memory(cpu: &mut Cpu, mem: &mut Memory, op: u32) -> u32 {
+        execute(cpu, mem, Instruction::decode_arm(op))
+    }
+
+    fn execute_thumb(cpu: &mut Cpu, op: u16) -> u32 {
+        execute_thumb_with_memory(cpu, &mut test_memory(), op)
+    }
+
+    fn execute_thumb_with_memory(cpu: &mut Cpu, mem: &mut Memory, op: u16) -> u32 {
+        execute(cpu, mem, Instruction::decode_thumb(op))
+    }
+
+    #[test]
+    fn test_add_registers() {
+        let mut cpu = Cpu::new();
+        cpu.r2 = 2;
+        cpu.r3 = 5;
+
+        // add r3, r3, r2
+        execute_arm(&mut cpu, 0xe0833002);
+
+        assert_eq!(7, cpu.r3);
+    }
+
+    #[test]
+    fn test_subs_flags() {
+        let mut cpu = Cpu::new();
+        cpu.r0 = 1;
+
+        // subs r1, r0, #1
+        execute_arm(&mut cpu, 0xe2501001);
+
+        assert_eq!(0, cpu.r1);
+        assert!(cpu.flag_z());
+        assert!(cpu.flag_c());
+        assert!(!cpu.flag_n());
+        assert!(!cpu.flag_v());
+
+        // subs r1, r1, #1 borrows
+        execute_arm(&mut cpu, 0xe2511001);
+
+        assert_eq!(0xffffffff, cpu.r1);
+        assert!(!cpu.flag_z());
+        assert!(!cpu.flag_c());
+        assert!(cpu.flag_n());
+    }
+
+    #[test]
+    fn test_adds_overflow() {
+        let mut cpu = Cpu::new();
+        cpu.r0 = 0x7fffffff;
+
+        // adds r0, r0, #1
+        execute_arm(&mut cpu, 0xe2900001);
+
+        assert_eq!(0x80000000, cpu.r0);
+        assert!(cpu.flag_n());
+        assert!(cpu.flag_v());
+        assert!(!cpu.flag_c());
+    }
+
+    #[test]
+    fn test_cmp_does_not_write_dest() {
+        let mut cpu = Cpu::new();
+        cpu.r0 = 5;
+
+        // cmp r0, #5
+        execute_arm(&mut cpu, 0xe3500005);
+
+        assert_eq!(5, cpu.r0);
+        assert!(cpu.flag_z());
+        assert!(cpu.flag_c());
+    }
+
+    #[test]
+    fn test_rsc_uses_carry() {
+        let mut cpu = Cpu::new();
+        cpu.r1 = 3;
+
+        // rsc r0, r1, #10 with C clear gives 10 - 3 - 1
+        execute_arm(&mut cpu, 0xe2e1000a);
+
+        assert_eq!(6, cpu.r0);
+    }
+
+    #[test]
+    fn test_movs_pc_restores_spsr() {
+        let mut cpu = Cpu::new();
+        cpu.r14 = 0x08000100;
+        cpu.set_spsr(0x6000001f);
+
+        // movs pc, lr
+        execute_arm(&mut cpu, 0xe1b0f00e);
+
+        assert_eq!(0x08000100, cpu.r15);
+        assert_eq!(0x6000001f, cpu.cpsr());
+    }
+
+    #[test]
+    fn test_mov_shifted_register() {
+        let mut cpu = Cpu::new();
+        cpu.r1 = 0x80000001;
+
+        // movs r0, r1, lsl #1
+        let cycles = execute_arm(&mut cpu, 0xe1b00081);
+
+        assert_eq!(0x2, cpu.r0);
+        assert!(cpu.flag_c());
+        assert_eq!(0, cycles);
+    }
+
+    #[test]
+    fn test_register_shift_extra_cycle() {
+        let mut cpu = Cpu::new();
+        cpu.r1 = 0x10;
+        cpu.r2 = 4;
+
+        // mov r0, r1, lsr r2
+        let cycles = execute_arm(&mut cpu, 0xe1a00231);
+
+        assert_eq!(0x1, cpu.r0);
+        assert_eq!(1, cycles);
+    }
+
+    #[test]
+    fn test_register_shift_reads_pc_ahead() {
+        let mut cpu = Cpu::new();
+        cpu.r15 = 0x08000008;
+        cpu.r2 = 0;
+
+        // add r0, pc, pc, lsl r2
+        execute_arm(&mut cpu, 0xe08f021f);
+
+        assert_eq!(0x08000008 * 2 + 8, cpu.r0);
+    }
+
+    #[test]
+    fn test_rrx_through_carry() {
+        let mut cpu = Cpu::new();
+        cpu.r1 = 0x3;
+        cpu.set_flags(false, false, true, false);
+
+        // movs r0, r1, rrx
+        execute_arm(&mut cpu, 0xe1b00061);
+
+        assert_eq!(0x80000001, cpu.r0);
+        assert!(cpu.flag_c());
+    }
+
+    #[test]
+    fn test_rotated_immediate_carry() {
+        let mut cpu = Cpu::new();
+
+        // movs r0, #0xf000000f
+        execute_arm(&mut cpu, 0xe3b002ff);
+
+        assert_eq!(0xf000000f, cpu.r0);
+        assert!(cpu.flag_c());
+        assert!(cpu.flag_n());
+    }
+
+    #[test]
+    fn test_condition_skips_instruction() {
+        let mut cpu = Cpu::new();
+
+        // moveq r0, #1 with Z clear
+        let cycles = execute_arm(&mut cpu, 0x03a00001);
+
+        assert_eq!(0, cpu.r0);
+        assert_eq!(0, cycles);
+
+        // movne r0, #1
+        execute_arm(&mut cpu, 0x13a00001);
+
+        assert_eq!(1, cpu.r0);
+    }
+
+    #[test]
+    fn test_signed_conditions() {
+        let mut cpu = Cpu::new();
+        cpu.r0 = 0xffffffff;
+
+        // cmp r0, #1: -1 < 1 signed, but higher unsigned
+        execute_arm(&mut cpu, 0xe3500001);
+
+        assert!(condition_passed(&cpu, &Condition::Lt));
+        assert!(condition_passed(&cpu, &Condition::Le));
+        assert!(!condition_passed(&cpu, &Condition::Gt));
+        assert!(!condition_passed(&cpu, &Condition::Ge));
+        assert!(condition_passed(&cpu, &Condition::UnsignedGt));
+        assert!(condition_passed(&cpu, &Condition::UnsignedGe));
+        assert!(!condition_passed(&cpu, &Condition::UnsignedLe));
+    }
+
+    #[test]
+    fn test_never_condition() {
+        let mut cpu = Cpu::new();
+
+        execute_arm(&mut cpu, 0xf3a00001);
+
+        assert_eq!(0, cpu.r0);
+    }
+
+    #[test]
+    fn test_bx_switches_to_thumb() {
+        let mut cpu = Cpu::new();
+        cpu.r0 = 0x08000101;
+
+        // bx r0
+        execute_arm(&mut cpu, 0xe12fff10);
+
+        assert_eq!(CpuState::Thumb, cpu.state());
+        assert!(cpu.cpsr() & (0b1 << 5) != 0);
+        assert_eq!(0x08000100, cpu.r15);
+
+        // bx r1 back to arm
+        cpu.r1 = 0x08000200;
+        execute_thumb(&mut cpu, 0x4708);
+
+        assert_eq!(CpuState::Arm, cpu.state());
+        assert!(cpu.cpsr() & (0b1 << 5) == 0);
+        assert_eq!(0x08000200, cpu.r15);
+    }
+
+    #[test]
+    fn test_thumb_branch_offset_in_halfwords() {
+        let mut cpu = Cpu::new();
+        cpu.set_state(CpuState::Thumb);
+        cpu.r15 = 0x08000104;
+
+        // b with an offset of -2 halfwords branches to itself
+        execute_thumb(&mut cpu, 0xe7fe);
+
+        assert_eq!(0x08000100, cpu.r15);
+    }
+
+    #[test]
+    fn test_thumb_long_branch_link() {
+        let mut cpu = Cpu::new();
+        cpu.set_state(CpuState::Thumb);
+
+        // bl from 0x08000100 to 0x08001000
+        cpu.r15 = 0x08000104;
+        execute_thumb(&mut cpu, 0xf000);
+        cpu.r15 = 0x08000106;
+        execute_thumb(&mut cpu, 0xff7e);
+
+        assert_eq!(0x08001000, cpu.r15);
+        assert_eq!(0x08000105, cpu.r14);
+
+        // and backwards, from 0x08001000 to 0x08000100
+        cpu.r15 = 0x08001004;
+        execute_thumb(&mut cpu, 0xf7ff);
+        cpu.r15 = 0x08001006;
+        execute_thumb(&mut cpu, 0xf87e);
+
+        assert_eq!(0x08000100, cpu.r15);
+        assert_eq!(0x08001005, cpu.r14);
+    }
+
+    #[test]
+    fn test_thumb_add_sub_low_registers() {
+        let mut cpu = Cpu::new();
+        cpu.set_state(CpuState::Thumb);
+        cpu.r1 = 5;
+        cpu.r2 = 7;
+
+        // adds r0, r1, r2
+        execute_thumb(&mut cpu, 0x1888);
+        assert_eq!(12, cpu.r0);
+
+        // subs r0, r1, #6
+        execute_thumb(&mut cpu, 0x1f88);
+        assert_eq!(0xffffffff, cpu.r0);
+        assert!(cpu.flag_n());
+        assert!(!cpu.flag_c());
+    }
+
+    #[test]
+    fn test_thumb_load_address_aligns_pc() {
+        let mut cpu = Cpu::new();
+        cpu.set_state(CpuState::Thumb);
+        cpu.r15 = 0x08000106;
+
+        // add r0, pc, #4
+        execute_thumb(&mut cpu, 0xa001);
+
+        assert_eq!(0x08000108, cpu.r0);
+    }
+
+    #[test]
+    fn test_thumb_mov_pc_stays_halfword_aligned() {
+        let mut cpu = Cpu::new();
+        cpu.set_state(CpuState::Thumb);
+        cpu.r0 = 0x08000203;
+
+        // mov pc, r0
+        execute_thumb(&mut cpu, 0x4687);
+
+        assert_eq!(CpuState::Thumb, cpu.state());
+        assert_eq!(0x08000202, cpu.r15);
+    }
+
+    #[test]
+    fn test_thumb_cmp_pc_only_sets_flags() {
+        let mut cpu = Cpu::new();
+        cpu.set_state(CpuState::Thumb);
+        cpu.set_spsr(0x0000001f);
+        cpu.r15 = 0x08000100;
+        cpu.r0 = 0x08000100;
+
+        // cmp pc, r0
+        execute_thumb(&mut cpu, 0x4587);
+
+        assert_eq!(CpuState::Thumb, cpu.state());
+        assert_eq!(0x08000100, cpu.r15);
+        assert!(cpu.flag_z());
+        assert!(cpu.flag_c());
+    }
+
+    #[test]
+    fn test_strh_ldrh() {
+        let mut cpu = Cpu::new();
+        let mut mem = test_memory();
+        cpu.r0 = 0x12345678;
+        cpu.r1 = 0x03000000;
+
+        // strh r0, [r1, #2]!
+        let cycles = execute_arm_with_memory(&mut cpu, &mut mem, 0xe1e100b2);
+
+        assert_eq!(0x03000002, cpu.r1);
+        assert_eq!(0x5678, mem.get_halfword(0x03000002));
+        assert_eq!(0, mem.get_halfword(0x03000000));
+        assert_eq!(0, cycles);
+
+        // ldrh r2, [r1], #-2
+        let cycles = execute_arm_with_memory(&mut cpu, &mut mem, 0xe05120b2);
+
+        assert_eq!(0x5678, cpu.r2);
+        assert_eq!(0x03000000, cpu.r1);
+        assert_eq!(1, cycles);
+    }
+
+    #[test]
+    fn test_ldrh_misaligned_rotates() {
+        let mut cpu = Cpu::new();
+        let mut mem = test_memory();
+        mem.set_halfword(0x03000000, 0xbeef);
+        cpu.r1 = 0x03000001;
+
+        // ldrh r0, [r1]
+        execute_arm_with_memory(&mut cpu, &mut mem, 0xe1d100b0);
+
+        assert_eq!(0xef0000be, cpu.r0);
+    }
+
+    #[test]
+    fn test_signed_loads() {
+        let mut cpu = Cpu::new();
+        let mut mem = test_memory();
+        mem.set_halfword(0x03000000, 0x80f0);
+        cpu.r1 = 0x03000000;
+
+        // ldrsb r0, [r1]
+        execute_arm_with_memory(&mut cpu, &mut mem, 0xe1d100d0);
+        assert_eq!(0xfffffff0, cpu.r0);
+
+        // ldrsh r0, [r1]
+        execute_arm_with_memory(&mut cpu, &mut mem, 0xe1d100f0);
+        assert_eq!(0xffff80f0, cpu.r0);
+
+        // ldrsh r0, [r1, #1] reads the byte as ldrsb would
+        execute_arm_with_memory(&mut cpu, &mut mem, 0xe1d100f1);
+        assert_eq!(0xffffff80, cpu.r0);
+    }
+
+    #[test]
+    fn test_thumb_ldrh_register_offset() {
+        let mut cpu = Cpu::new();
+        let mut mem = test_memory();
+        cpu.set_state(CpuState::Thumb);
+        mem.set_halfword(0x03000010, 0xcafe);
+        cpu.r1 = 0x03000000;
+        cpu.r2 = 0x10;
+
+        // ldrh r0, [r1, r2]
+        execute_thumb_with_memory(&mut cpu, &mut mem, 0x5a88);
+
+        assert_eq!(0xcafe, cpu.r0);
+    }
+
+    #[test]
+    fn test_push_pop_word() {
+        let mut cpu = Cpu::new();
+        let mut mem = test_memory();
+        cpu.r11 = 0x1234;
+        cpu.r13 = 0x03007f00;
+
+        // str r11, [sp, #-4]!
+        let cycles = execute_arm_with_memory(&mut cpu, &mut mem, 0xe52db004);
+
+        assert_eq!(0x03007efc, cpu.r13);
+        assert_eq!(0x1234, mem.get_word(0x03007efc));
+        assert_eq!(0, cycles);
+
+        // ldr r11, [sp], #4
+        cpu.r11 = 0;
+        let cycles = execute_arm_with_memory(&mut cpu, &mut mem, 0xe49db004);
+
+        assert_eq!(0x03007f00, cpu.r13);
+        assert_eq!(0x1234, cpu.r11);
+        assert_eq!(1, cycles);
+    }
+
+    #[test]
+    fn test_ldr_misaligned_rotates() {
+        let mut cpu = Cpu::new();
+        let mut mem = test_memory();
+        mem.set_word(0x03000000, 0xdeadbeef);
+
+        let expected = [0xdeadbeef, 0xefdeadbe, 0xbeefdead, 0xadbeefde];
+
+        for (i, expected) in expected.iter().enumerate() {
+            cpu.r1 = 0x03000000 + i as u32;
+
+            // ldr r0, [r1]
+            execute_arm_with_memory(&mut cpu, &mut mem, 0xe5910000);
+
+            assert_eq!(*expected, cpu.r0);
+        }
+    }
+
+    #[test]
+    fn test_byte_transfers() {
+        let mut cpu = Cpu::new();
+        let mut mem = test_memory();
+        cpu.r0 = 0x12345678;
+        cpu.r1 = 0x03000000;
+        cpu.r2 = 3;
+
+        // strb r0, [r1, r2]
+        execute_arm_with_memory(&mut cpu, &mut mem, 0xe7c10002);
+        assert_eq!(0x78000000, mem.get_word(0x03000000));
+
+        // ldrb r3, [r1, r2, lsl #0]
+        execute_arm_with_memory(&mut cpu, &mut mem, 0xe7d13002);
+        assert_eq!(0x78, cpu.r3);
+    }
+
+    #[test]
+    fn test_scaled_register_offset() {
+        let mut cpu = Cpu::new();
+        let mut mem = test_memory();
+        mem.set_word(0x03000008, 0xabcd);
+        cpu.r1 = 0x03000000;
+        cpu.r2 = 2;
+
+        // ldr r0, [r1, r2, lsl #2]
+        execute_arm_with_memory(&mut cpu, &mut mem, 0xe7910102);
+
+        assert_eq!(0xabcd, cpu.r0);
+    }
+
+    #[test]
+    fn test_ldr_pc_branches() {
+        let mut cpu = Cpu::new();
+        let mut mem = test_memory();
+        mem.set_word(0x03000000, 0x08000400);
+        cpu.r1 = 0x03000000;
+
+        // ldr pc, [r1]
+        let cycles = execute_arm_with_memory(&mut cpu, &mut mem, 0xe591f000);
+
+        assert_eq!(0x08000400, cpu.r15);
+        assert_eq!(1, cycles);
+    }
+
+    #[test]
+    fn test_str_pc_stores_ahead() {
+        let mut cpu = Cpu::new();
+        let mut mem = test_memory();
+        cpu.r15 = 0x08000108;
+        cpu.r1 = 0x03000000;
+
+        // str pc, [r1]
+        execute_arm_with_memory(&mut cpu, &mut mem, 0xe581f000);
+
+        assert_eq!(0x0800010c, mem.get_word(0x03000000));
+    }
+
+    #[test]
+    fn test_thumb_pc_relative_load() {
+        let mut cpu = Cpu::new();
+        let mut mem = test_memory();
+        cpu.set_state(CpuState::Thumb);
+        mem.set_word(0x03000108, 0x55aa);
+
+        // ldr r0, [pc, #4] at 0x03000102
+        cpu.r15 = 0x03000106;
+        execute_thumb_with_memory(&mut cpu, &mut mem, 0x4801);
+
+        assert_eq!(0x55aa, cpu.r0);
+    }
+
+    #[test]
+    fn test_stmdb_ldmia_push_pop() {
+        let mut cpu = Cpu::new();
+        let mut mem = test_memory();
+        cpu.r11 = 0x1111;
+        cpu.r14 = 0x08000200;
+        cpu.r13 = 0x03007f00;
+
+        // push {r11, lr}
+        let cycles = execute_arm_with_memory(&mut cpu, &mut mem, 0xe92d4800);
+
+        assert_eq!(0x03007ef8, cpu.r13);
+        assert_eq!(0x1111, mem.get_word(0x03007ef8));
+        assert_eq!(0x08000200, mem.get_word(0x03007efc));
+        assert_eq!(0, cycles);
+
+        // pop {r11, pc}
+        cpu.r11 = 0;
+        let cycles = execute_arm_with_memory(&mut cpu, &mut mem, 0xe8bd8800);
+
+        assert_eq!(0x03007f00, cpu.r13);
+        assert_eq!(0x1111, cpu.r11);
+        assert_eq!(0x08000200, cpu.r15);
+        assert_eq!(1, cycles);
+    }
+
+    #[test]
+    fn test_block_addressing_modes() {
+        let mut mem = test_memory();
+
+        // (instruction, first address, written back base) for {r1, r2} with base r0
+        let modes = [
+            (0xe8a00006, 0x03000100, 0x03000108), // stmia r0!
+            (0xe9a00006, 0x03000104, 0x03000108), // stmib r0!
+            (0xe8200006, 0x030000fc, 0x030000f8), // stmda r0!
+            (0xe9200006, 0x030000f8, 0x030000f8), // stmdb r0!
+        ];
+
+        for (op, first, written_back) in modes.iter() {
+            let mut cpu = Cpu::new();
+            cpu.r0 = 0x03000100;
+            cpu.r1 = 0x11;
+            cpu.r2 = 0x22;
+
+            execute_arm_with_memory(&mut cpu, &mut mem, *op);
+
+            assert_eq!(*written_back, cpu.r0);
+            assert_eq!(0x11, mem.get_word(*first));
+            assert_eq!(0x22, mem.get_word(first + 4));
+        }
+    }
+
+    #[test]
+    fn test_empty_register_list() {
+        let mut cpu = Cpu::new();
+        let mut mem = test_memory();
+        cpu.r0 = 0x03000100;
+        cpu.r15 = 0x08000108;
+
+        // stmia r0!, {}
+        execute_arm_with_memory(&mut cpu, &mut mem, 0xe8a00000);
+
+        assert_eq!(0x03000140, cpu.r0);
+        assert_eq!(0x0800010c, mem.get_word(0x03000100));
+
+        // ldmdb r0!, {}
+        mem.set_word(0x03000100, 0x08000400);
+        execute_arm_with_memory(&mut cpu, &mut mem, 0xe9300000);
+
+        assert_eq!(0x03000100, cpu.r0);
+        assert_eq!(0x08000400, cpu.r15);
+    }
+
+    #[test]
+    fn test_base_in_register_list() {
+        let mut mem = test_memory();
+
+        // stmia r0!, {r0, r1} stores the original base when it is first
+        let mut cpu = Cpu::new();
+        cpu.r0 = 0x03000100;
+        execute_arm_with_memory(&mut cpu, &mut mem, 0xe8a00003);
+        assert_eq!(0x03000100, mem.get_word(0x03000100));
+
+        // stmia r1!, {r0, r1} stores the written back base when it isn't
+        let mut cpu = Cpu::new();
+        cpu.r1 = 0x03000200;
+        execute_arm_with_memory(&mut cpu, &mut mem, 0xe8a10003);
+        assert_eq!(0x03000208, mem.get_word(0x03000204));
+
+        // ldmia r0!, {r0, r1} keeps the loaded value
+        let mut cpu = Cpu::new();
+        cpu.r0 = 0x03000100;
+        execute_arm_with_memory(&mut cpu, &mut mem, 0xe8b00003);
+        assert_eq!(0x03000100, cpu.r0);
+    }
+
+    #[test]
+    fn test_force_psr_transfers() {
+        let mut cpu = Cpu::new();
+        let mut mem = test_memory();
+        cpu.set_cpsr(0xd0);
+        cpu.r13 = 0x03007f00;
+        cpu.set_cpsr(0xd2);
+        cpu.r13 = 0x03007fa0;
+        cpu.r0 = 0x03000100;
+
+        // stmia r0, {sp}^ stores the user stack pointer
+        execute_arm_with_memory(&mut cpu, &mut mem, 0xe8c02000);
+        assert_eq!(0x03007f00, mem.get_word(0x03000100));
+
+        // ldmia r0, {r1, pc}^ restores the SPSR
+        mem.set_word(0x03000100, 0x11);
+        mem.set_word(0x03000104, 0x08000300);
+        cpu.set_spsr(0x1f);
+        execute_arm_with_memory(&mut cpu, &mut mem, 0xe8d08002);
+
+        assert_eq!(0x11, cpu.r1);
+        assert_eq!(0x08000300, cpu.r15);
+        assert_eq!(0x1f, cpu.cpsr());
+        assert_eq!(0x03007f00, cpu.r13);
+    }
+
+    #[test]
+    fn test_mul_mla() {
+        let mut cpu = Cpu::new();
+        cpu.r2 = 7;
+        cpu.r3 = 10;
+
+        // mul r3, r2, r3
+        let cycles = execute_arm(&mut cpu, 0xe0030392);
+
+        assert_eq!(70, cpu.r3);
+        assert_eq!(1, cycles);
+
+        // mlas r0, r2, r3, r1
+        cpu.r1 = 0xffffffff;
+        let cycles = execute_arm(&mut cpu, 0xe0301392);
+
+        assert_eq!(7 * 70 - 1, cpu.r0);
+        assert!(!cpu.flag_n());
+        assert!(!cpu.flag_z());
+        assert_eq!(2, cycles);
+    }
+
+    #[test]
+    fn test_multiply_long() {
+        let mut cpu = Cpu::new();
+        cpu.r2 = 0xffffffff;
+        cpu.r3 = 2;
+
+        // umull r0, r1, r2, r3
+        execute_arm(&mut cpu, 0xe0810392);
+        assert_eq!(0xfffffffe, cpu.r0);
+        assert_eq!(0x1, cpu.r1);
+
+        // smull r0, r1, r2, r3 is -1 * 2
+        execute_arm(&mut cpu, 0xe0c10392);
+        assert_eq!(0xfffffffe, cpu.r0);
+        assert_eq!(0xffffffff, cpu.r1);
+
+        // umlals r0, r1, r2, r3 adds onto r1:r0
+        cpu.r0 = 2;
+        cpu.r1 = 0xfffffffe;
+        execute_arm(&mut cpu, 0xe0b10392);
+        assert_eq!(0, cpu.r0);
+        assert_eq!(0, cpu.r1);
+        assert!(cpu.flag_z());
+        assert!(!cpu.flag_n());
+
+        // smulls r0, r1, r2, r2 is -1 * -1
+        execute_arm(&mut cpu, 0xe0d10292);
+        assert_eq!(1, cpu.r0);
+        assert_eq!(0, cpu.r1);
+    }
+
+    #[test]
+    fn test_multiplier_early_termination() {
+        assert_eq!(1, multiplier_cycles(0xff, false));
+        assert_eq!(2, multiplier_cycles(0x100, false));
+        assert_eq!(3, multiplier_cycles(0x10000, false));
+        assert_eq!(4, multiplier_cycles(0x1000000, false));
+
+        // Leading ones only terminate early for signed multiplies
+        assert_eq!(1, multiplier_cycles(0xffffff80, true));
+        assert_eq!(2, multiplier_cycles(0xffff8000, true));
+        assert_eq!(4, multiplier_cycles(0xffffff80, false));
+    }
+
+    #[test]
+    fn test_multiply_long_cycles() {
+        let mut cpu = Cpu::new();
+        cpu.r2 = 0xffffffff;
+        cpu.r3 = 0x12345678;
+
+        // umull r0, r1, r3, r2 with an all ones multiplier takes the full 4 cycles
+        assert_eq!(5, execute_arm(&mut cpu, 0xe0810293));
+
+        // smlal r0, r1, r3, r2 terminates after one
+        assert_eq!(3, execute_arm(&mut cpu, 0xe0e10293));
+    }
+
+    #[test]
+    fn test_swap_word() {
+        let mut cpu = Cpu::new();
+        let mut mem = test_memory();
+        mem.set_word(0x03000000, 0xdeadbeef);
+        cpu.r0 = 0x03000000;
+        cpu.r1 = 0x12345678;
+
+        // swp r1, r1, [r0]
+        let cycles = execute_arm_with_memory(&mut cpu, &mut mem, 0xe1001091);
+
+        assert_eq!(0xdeadbeef, cpu.r1);
+        assert_eq!(0x12345678, mem.get_word(0x03000000));
+        assert_eq!(1, cycles);
+        assert!(!mem.is_locked());
+    }
+
+    #[test]
+    fn test_swap_misaligned_and_byte() {
+        let mut cpu = Cpu::new();
+        let mut mem = test_memory();
+        mem.set_word(0x03000000, 0xdeadbeef);
+        cpu.r0 = 0x03000001;
+        cpu.r1 = 0x11223344;
+
+        // swp r2, r1, [r0] rotates the read and stores aligned
+        execute_arm_with_memory(&mut cpu, &mut mem, 0xe1002091);
+
+        assert_eq!(0xefdeadbe, cpu.r2);
+        assert_eq!(0x11223344, mem.get_word(0x03000000));
+
+        // swpb r2, r1, [r0]
+        execute_arm_with_memory(&mut cpu, &mut mem, 0xe1402091);
+
+        assert_eq!(0x33, cpu.r2);
+        assert_eq!(0x11224444, mem.get_word(0x03000000));
+    }
+
+    #[test]
+    fn test_msr_sets_up_irq_stack() {
+        let mut cpu = Cpu::new();
+
+        // mov r0, #0x12; msr cpsr_fc, r0; mov sp, #0x03000000
+        execute_arm(&mut cpu, 0xe3a00012);
+        execute_arm(&mut cpu, 0xe129f000);
+        execute_arm(&mut cpu, 0xe3a0d403);
+
+        assert_eq!(Mode::Irq, cpu.mode());
+        assert_eq!(0x03000000, cpu.r13);
+
+        // mov r0, #0x1f; msr cpsr_fc, r0
+        execute_arm(&mut cpu, 0xe3a0001f);
+        execute_arm(&mut cpu, 0xe129f000);
+
+        assert_eq!(Mode::System, cpu.mode());
+        assert_eq!(0, cpu.r13);
+
+        // mrs r1, cpsr
+        execute_arm(&mut cpu, 0xe10f1000);
+        assert_eq!(0x1f, cpu.r1);
+    }
+
+    #[test]
+    fn test_msr_user_mode_flags_only() {
+        let mut cpu = Cpu::new();
+        cpu.set_cpsr(0x10);
+        cpu.r0 = 0xf000001f;
+
+        // msr cpsr_fc, r0
+        execute_arm(&mut cpu, 0xe129f000);
+
+        assert_eq!(Mode::User, cpu.mode());
+        assert_eq!(0xf0000010, cpu.cpsr());
+    }
+
+    #[test]
+    fn test_msr_flags_field_mask() {
+        let mut cpu = Cpu::new();
+
+        // msr cpsr_f, #0xf0000000
+        execute_arm(&mut cpu, 0xe328f20f);
+
+        assert_eq!(0xf00000d3, cpu.cpsr());
+
+        // msr spsr_fc, r0 ; mrs r1, spsr
+        cpu.r0 = 0x8000001f;
+        execute_arm(&mut cpu, 0xe169f000);
+        execute_arm(&mut cpu, 0xe14f1000);
+
+        assert_eq!(0x8000001f, cpu.r1);
+    }
+
+    #[test]
+    fn test_swi_enters_supervisor() {
+        let mut cpu = Cpu::new();
+        cpu.set_cpsr(0x1f);
+        cpu.r15 = 0x08000108;
+
+        // swi 0x60000
+        let cycles = execute_arm(&mut cpu, 0xef060000);
+
+        assert_eq!(Mode::Supervisor, cpu.mode());
+        assert_eq!(0x08, cpu.r15);
+        assert_eq!(0x08000104, cpu.r14);
+        assert_eq!(0x1f, cpu.spsr());
+        assert_eq!(0, cycles);
+
+        // movs pc, lr returns to the next instruction in system mode
+        execute_arm(&mut cpu, 0xe1b0f00e);
+
+        assert_eq!(Mode::System, cpu.mode());
+        assert_eq!(0x08000104, cpu.r15);
+    }
+
+    #[test]
+    fn test_undefined_instruction_trap() {
+        let mut cpu = Cpu::new();
+        cpu.r15 = 0x08000108;
+
+        execute_arm(&mut cpu, 0xe7f000f0);
+
+        assert_eq!(Mode::Undefined, cpu.mode());
+        assert_eq!(0x04, cpu.r15);
+        assert_eq!(0x08000104, cpu.r14);
+    }
+
+    #[test]
+    fn test_swi_with_hle_bios() {
+        let mut cpu = Cpu::new();
+        cpu.skip_bios();
+        cpu.set_hle_bios(true);
+        let mut mem = Memory::new_with_rom(vec![]);
+
+        cpu.r0 = 100;
+        cpu.r1 = 7;
+        cpu.r15 = 0x08000108;
+
+        // swi 0x60000, Div
+        execute_arm_with_memory(&mut cpu, &mut mem, 0xef060000);
+
+        assert_eq!(Mode::System, cpu.mode());
+        assert_eq!(0x08000108, cpu.r15);
+        assert_eq!(14, cpu.r0);
+        assert_eq!(2, cpu.r1);
+
+        // swi 0x08, Sqrt from thumb
+        cpu.set_state(CpuState::Thumb);
+        cpu.r0 = 144;
+        execute_thumb_with_memory(&mut cpu, &mut mem, 0xdf08);
+
+        assert_eq!(12, cpu.r0);
+
+        // Back in the cartridge, the BIOS reads as it was left by the SWI
+        let n = Access::NonSequential;
+        mem.read_16(0x0800010a, n, AccessKind::Opcode);
+        assert_eq!(0xe3a02004, mem.read_32(0, n, AccessKind::Data));
+    }
 }
@@ -24,6 +24,7 @@ pub enum Condition {
     Gt,
     Le,
     Always,
+    Never,
 }
 
 #[derive(Debug, PartialEq)]
@@ -80,6 +81,30 @@ pub enum InstructionOp {
         pre_index: bool,
         register_list: Vec<Register>,
     },
+    HalfwordDataTransfer {
+        base: Register,
+        source_dest: Register,
+        load: bool, // false = store
+        write_back: bool,
+        signed: bool,
+        halfword: bool, // false = byte
+        add_offset: bool,
+        pre_index: bool,
+        offset: Offset, // Immediate offsets are 8 bits, register offsets are never shifted
+    },
+    SoftwareInterrupt {
+        comment: u32, // 24 bits (arm) or 8 bits (thumb)
+    },
+    Undefined,
+    MoveFromPsr {
+        dest: Register,
+        spsr: bool, // false = cpsr
+    },
+    MoveToPsr {
+        source: Operand, // Register operands are never shifted
+        spsr: bool,      // false = cpsr
+        fields: u8,      // 4 bits, one per byte of the psr. flags (bit 3) to control (bit 0)
+    },
 }
 
 #[derive(Debug, PartialEq)]
@@ -91,6 +116,13 @@ pub enum Branch {
         offset: u32, // 24 bits
         link: bool,  // if true, write next instr addr to link/r14 register
     },
+    // Thumb BL is split over two instructions, each carrying 11 bits of the offset
+    LongLinkHigh {
+        offset: u32, // 11 bits
+    },
+    LongLinkLow {
+        offset: u32, // 11 bits
+    },
 }
 
 #[derive(Debug, PartialEq)]
@@ -149,6 +181,27 @@ impl Instruction {
     }
 }
 
+impl Instruction {
+    pub fn decode_thumb(instr: u16) -> Instruction {
+        log::trace!("Decoding thumb {:4x}", instr);
+
+        let op = instr as u32;
+
+        if op >> 12 == 0b1101 && (op >> 8) & 0b1111 < 0b1110 {
+            // Conditional branch is the only thumb instruction that isn't always executed
+            return Instruction {
+                condition: read_condition((op >> 8) & 0b1111),
+                instruction: decode_thumb_conditional_branch(op),
+            };
+        }
+
+        Instruction {
+            condition: Condition::Always,
+            instruction: read_thumb_instruction_op(op),
+        }
+    }
+}
+
 fn read_condition(cond: u32) -> Condition {
     match cond {
         0 => Condition::Equal,
@@ -166,6 +219,7 @@ fn read_condition(cond: u32) -> Condition {
         12 => Condition::Gt,
         13 => Condition::Le,
         14 => Condition::Always,
+        15 => Condition::Never,
         _ => panic!("read_condition unexpectedly called with: {:b}", cond),
     }
 }
@@ -174,20 +228,23 @@ fn read_instruction_op(op: u32) -> InstructionOp {
     let bits = op.view_bits::<Lsb0>();
     let b_27_26 = op >> 26;
 
+    // TST/TEQ/CMP/CMN without the S bit encode PSR transfers
+    let psr_transfer = bits[24] && !bits[23] && !bits[20];
+
     match b_27_26 {
         0b00 => {
             if bits[25] {
-                // decode Data Processing with Immediate operand2
-                decode_data_processing(true, op)
+                if psr_transfer {
+                    decode_psr_transfer(true, op)
+                } else {
+                    // decode Data Processing with Immediate operand2
+                    decode_data_processing(true, op)
+                }
             } else {
                 if bits[4] {
                     if bits[7] {
                         if bits[5] || bits[6] {
-                            if bits[22] {
-                                unimplemented!("Halfword transfer immediate offset");
-                            } else {
-                                unimplemented!("Halfword transfer register offset");
-                            }
+                            decode_halfword_data_transfer(bits[22], op)
                         } else {
                             if bits[24] {
                                 decode_swap(op)
@@ -199,17 +256,22 @@ fn read_instruction_op(op: u32) -> InstructionOp {
                                 }
                             }
                         }
-                    } else {
+                    } else if op & 0x0FFFFFF0 == 0x012FFF10 {
                         decode_branch_exchange(op)
+                    } else {
+                        // Data processing with a register specified shift
+                        decode_data_processing(false, op)
                     }
+                } else if psr_transfer {
+                    decode_psr_transfer(false, op)
                 } else {
-                    return decode_data_processing(false, op);
+                    decode_data_processing(false, op)
                 }
             }
         }
         0b01 => {
             if bits[25] && bits[4] {
-                unimplemented!("Undefined instruction");
+                InstructionOp::Undefined
             } else {
                 decode_single_data_transfer(op)
             }
@@ -222,7 +284,14 @@ fn read_instruction_op(op: u32) -> InstructionOp {
             }
         }
         0b11 => {
-            unimplemented!("Coprocessor / Software interrupt");
+            if bits[25] && bits[24] {
+                InstructionOp::SoftwareInterrupt {
+                    comment: op & 0b11111111_11111111_11111111,
+                }
+            } else {
+                // There are no coprocessors, so their instructions take the undefined trap
+                InstructionOp::Undefined
+            }
         }
         _ => unimplemented!("bad read_instruction_op input: {} {}", op, b_27_26),
     }
@@ -260,6 +329,35 @@ fn decode_data_processing(immediate: bool, bits: u32) -> InstructionOp {
     }
 }
 
+fn decode_psr_transfer(immediate: bool, bits: u32) -> InstructionOp {
+    let spsr = ((bits >> 22) & 0b1) != 0;
+
+    if (bits >> 21) & 0b1 == 0 {
+        return InstructionOp::MoveFromPsr {
+            dest: read_register(((bits >> 12) & 0b1111) as u8),
+            spsr,
+        };
+    }
+
+    let source = if immediate {
+        Operand::Immediate {
+            rotate: ((bits >> 8) & 0b1111) as u8,
+            value: (bits & 0b11111111) as u8,
+        }
+    } else {
+        Operand::Register {
+            shift: 0,
+            register: read_register((bits & 0b1111) as u8),
+        }
+    };
+
+    InstructionOp::MoveToPsr {
+        source,
+        spsr,
+        fields: ((bits >> 16) & 0b1111) as u8,
+    }
+}
+
 fn decode_single_data_transfer(bits: u32) -> InstructionOp {
     let offset = if ((bits >> 25) & 0b1) != 0 {
         Offset::Register {
@@ -284,6 +382,31 @@ fn decode_single_data_transfer(bits: u32) -> InstructionOp {
     }
 }
 
+fn decode_halfword_data_transfer(immediate: bool, bits: u32) -> InstructionOp {
+    let offset = if immediate {
+        Offset::Immediate {
+            offset: (((bits >> 4) & 0b1111_0000) | (bits & 0b1111)) as u16,
+        }
+    } else {
+        Offset::Register {
+            shift: 0,
+            register: read_register((bits & 0b1111) as u8),
+        }
+    };
+
+    InstructionOp::HalfwordDataTransfer {
+        base: read_register(((bits >> 16) & 0b1111) as u8),
+        source_dest: read_register(((bits >> 12) & 0b1111) as u8),
+        load: ((bits >> 20) & 0b1) != 0,
+        write_back: ((bits >> 21) & 0b1) != 0,
+        signed: ((bits >> 6) & 0b1) != 0,
+        halfword: ((bits >> 5) & 0b1) != 0,
+        add_offset: ((bits >> 23) & 0b1) != 0,
+        pre_index: ((bits >> 24) & 0b1) != 0,
+        offset,
+    }
+}
+
 fn decode_branch_exchange(bits: u32) -> InstructionOp {
     InstructionOp::Branch {
         branch: Branch::Exchange {
@@ -355,6 +478,472 @@ fn decode_block_data_transfer(bits: u32) -> InstructionOp {
     }
 }
 
+fn read_thumb_instruction_op(op: u32) -> InstructionOp {
+    match op >> 13 {
+        0b000 => {
+            if (op >> 11) & 0b11 == 0b11 {
+                decode_thumb_add_subtract(op)
+            } else {
+                decode_thumb_move_shifted_register(op)
+            }
+        }
+        0b001 => decode_thumb_immediate(op),
+        0b010 => {
+            if op >> 10 == 0b010000 {
+                decode_thumb_alu(op)
+            } else if op >> 10 == 0b010001 {
+                decode_thumb_hi_register(op)
+            } else if op >> 11 == 0b01001 {
+                decode_thumb_pc_relative_load(op)
+            } else if (op >> 9) & 0b1 == 0 {
+                decode_thumb_load_store_register_offset(op)
+            } else {
+                decode_thumb_load_store_sign_extended(op)
+            }
+        }
+        0b011 => decode_thumb_load_store_immediate_offset(op),
+        0b100 => {
+            if (op >> 12) & 0b1 == 0 {
+                decode_thumb_load_store_halfword(op)
+            } else {
+                decode_thumb_sp_relative_load_store(op)
+            }
+        }
+        0b101 => {
+            if (op >> 12) & 0b1 == 0 {
+                decode_thumb_load_address(op)
+            } else if (op >> 8) & 0b1111 == 0b0000 {
+                decode_thumb_add_offset_to_sp(op)
+            } else if (op >> 9) & 0b11 == 0b10 {
+                decode_thumb_push_pop(op)
+            } else {
+                InstructionOp::Undefined
+            }
+        }
+        0b110 => {
+            if (op >> 12) & 0b1 == 0 {
+                decode_thumb_multiple_load_store(op)
+            } else if (op >> 8) & 0b1111 == 0b1111 {
+                InstructionOp::SoftwareInterrupt {
+                    comment: op & 0b11111111,
+                }
+            } else {
+                InstructionOp::Undefined
+            }
+        }
+        0b111 => {
+            if (op >> 11) & 0b11 == 0b00 {
+                decode_thumb_unconditional_branch(op)
+            } else if (op >> 11) & 0b11 == 0b01 {
+                InstructionOp::Undefined
+            } else {
+                decode_thumb_long_branch_link(op)
+            }
+        }
+        _ => unreachable!(),
+    }
+}
+
+// Format 1: LSL/LSR/ASR Rd, Rs, #Offset5
+fn decode_thumb_move_shifted_register(bits: u32) -> InstructionOp {
+    let shift_type = (bits >> 11) & 0b11;
+    let amount = (bits >> 6) & 0b11111;
+
+    InstructionOp::DataProcessing {
+        opcode: DataProcessingOpCode::Mov,
+        dest: read_thumb_low_register(bits),
+        operand1: Register::R0,
+        operand2: Operand::Register {
+            shift: ((amount << 3) | (shift_type << 1)) as u8,
+            register: read_thumb_low_register(bits >> 3),
+        },
+        alter_condition: true,
+    }
+}
+
+// Format 2: ADD/SUB Rd, Rs, Rn/#Offset3
+fn decode_thumb_add_subtract(bits: u32) -> InstructionOp {
+    let immediate = (bits >> 10) & 0b1 != 0;
+    let subtract = (bits >> 9) & 0b1 != 0;
+
+    let operand2 = if immediate {
+        Operand::Immediate {
+            rotate: 0,
+            value: ((bits >> 6) & 0b111) as u8,
+        }
+    } else {
+        Operand::Register {
+            shift: 0,
+            register: read_thumb_low_register(bits >> 6),
+        }
+    };
+
+    InstructionOp::DataProcessing {
+        opcode: if subtract {
+            DataProcessingOpCode::Sub
+        } else {
+            DataProcessingOpCode::Add
+        },
+        dest: read_thumb_low_register(bits),
+        operand1: read_thumb_low_register(bits >> 3),
+        operand2,
+        alter_condition: true,
+    }
+}
+
+// Format 3: MOV/CMP/ADD/SUB Rd, #Offset8
+fn decode_thumb_immediate(bits: u32) -> InstructionOp {
+    let rd = read_thumb_low_register(bits >> 8);
+
+    let opcode = match (bits >> 11) & 0b11 {
+        0b00 => DataProcessingOpCode::Mov,
+        0b01 => DataProcessingOpCode::Cmp,
+        0b10 => DataProcessingOpCode::Add,
+        _ => DataProcessingOpCode::Sub,
+    };
+
+    InstructionOp::DataProcessing {
+        opcode,
+        dest: rd,
+        operand1: rd,
+        operand2: Operand::Immediate {
+            rotate: 0,
+            value: (bits & 0b11111111) as u8,
+        },
+        alter_condition: true,
+    }
+}
+
+// Format 4: ALU operations on low registers, Rd := Rd op Rs
+fn decode_thumb_alu(bits: u32) -> InstructionOp {
+    let rd = read_thumb_low_register(bits);
+    let rs = read_thumb_low_register(bits >> 3);
+
+    let source = Operand::Register {
+        shift: 0,
+        register: rs,
+    };
+
+    // Shifts are a MOV of Rd shifted by the amount in Rs
+    let shift_by_rs = |shift_type: u32| Operand::Register {
+        shift: ((((bits >> 3) & 0b111) << 4) | (shift_type << 1) | 0b1) as u8,
+        register: rd,
+    };
+
+    let (opcode, operand1, operand2) = match (bits >> 6) & 0b1111 {
+        0b0000 => (DataProcessingOpCode::And, rd, source),
+        0b0001 => (DataProcessingOpCode::Eor, rd, source),
+        0b0010 => (DataProcessingOpCode::Mov, Register::R0, shift_by_rs(0b00)),
+        0b0011 => (DataProcessingOpCode::Mov, Register::R0, shift_by_rs(0b01)),
+        0b0100 => (DataProcessingOpCode::Mov, Register::R0, shift_by_rs(0b10)),
+        0b0101 => (DataProcessingOpCode::Adc, rd, source),
+        0b0110 => (DataProcessingOpCode::Sbc, rd, source),
+        0b0111 => (DataProcessingOpCode::Mov, Register::R0, shift_by_rs(0b11)),
+        0b1000 => (DataProcessingOpCode::Tst, rd, source),
+        0b1001 => (
+            // NEG Rd, Rs is Rd := 0 - Rs
+            DataProcessingOpCode::Rsb,
+            rs,
+            Operand::Immediate {
+                rotate: 0,
+                value: 0,
+            },
+        ),
+        0b1010 => (DataProcessingOpCode::Cmp, rd, source),
+        0b1011 => (DataProcessingOpCode::Cmn, rd, source),
+        0b1100 => (DataProcessingOpCode::Orr, rd, source),
+        0b1101 => {
+            // MUL Rd, Rs is Rd := Rs * Rd. Rd goes in the multiplier's timing operand
+            return InstructionOp::Multiply {
+                dest: rd,
+                operand1: rs,
+                operand2: rd,
+                accumulate: false,
+                acc_operand: Register::R0,
+                alter_condition: true,
+            };
+        }
+        0b1110 => (DataProcessingOpCode::Bic, rd, source),
+        _ => (DataProcessingOpCode::Mvn, Register::R0, source),
+    };
+
+    InstructionOp::DataProcessing {
+        opcode,
+        dest: rd,
+        operand1,
+        operand2,
+        alter_condition: true,
+    }
+}
+
+// Format 5: ADD/CMP/MOV/BX with access to r8-r15
+fn decode_thumb_hi_register(bits: u32) -> InstructionOp {
+    let rd = read_register((((bits >> 4) & 0b1000) | (bits & 0b111)) as u8);
+    let rs = read_register(((bits >> 3) & 0b1111) as u8);
+
+    let source = Operand::Register {
+        shift: 0,
+        register: rs,
+    };
+
+    // Only CMP sets the condition codes
+    let (opcode, alter_condition) = match (bits >> 8) & 0b11 {
+        0b00 => (DataProcessingOpCode::Add, false),
+        0b01 => (DataProcessingOpCode::Cmp, true),
+        0b10 => (DataProcessingOpCode::Mov, false),
+        _ => {
+            return InstructionOp::Branch {
+                branch: Branch::Exchange { register: rs },
+            }
+        }
+    };
+
+    InstructionOp::DataProcessing {
+        opcode,
+        dest: rd,
+        operand1: rd,
+        operand2: source,
+        alter_condition,
+    }
+}
+
+// Format 6: LDR Rd, [PC, #Imm]
+fn decode_thumb_pc_relative_load(bits: u32) -> InstructionOp {
+    InstructionOp::SingleDataTransfer {
+        base: Register::R15,
+        source_dest: read_thumb_low_register(bits >> 8),
+        load: true,
+        write_back: false,
+        write_byte: false,
+        add_offset: true,
+        pre_index: true,
+        offset: Offset::Immediate {
+            offset: ((bits & 0b11111111) << 2) as u16,
+        },
+    }
+}
+
+// Format 7: LDR/STR{B} Rd, [Rb, Ro]
+fn decode_thumb_load_store_register_offset(bits: u32) -> InstructionOp {
+    InstructionOp::SingleDataTransfer {
+        base: read_thumb_low_register(bits >> 3),
+        source_dest: read_thumb_low_register(bits),
+        load: (bits >> 11) & 0b1 != 0,
+        write_back: false,
+        write_byte: (bits >> 10) & 0b1 != 0,
+        add_offset: true,
+        pre_index: true,
+        offset: Offset::Register {
+            shift: 0,
+            register: read_thumb_low_register(bits >> 6),
+        },
+    }
+}
+
+// Format 8: STRH/LDRH/LDSB/LDSH Rd, [Rb, Ro]
+fn decode_thumb_load_store_sign_extended(bits: u32) -> InstructionOp {
+    let h = (bits >> 11) & 0b1 != 0;
+    let signed = (bits >> 10) & 0b1 != 0;
+
+    InstructionOp::HalfwordDataTransfer {
+        base: read_thumb_low_register(bits >> 3),
+        source_dest: read_thumb_low_register(bits),
+        // S=0 H=0 is STRH, every other combination loads
+        load: signed || h,
+        write_back: false,
+        signed,
+        halfword: h || !signed,
+        add_offset: true,
+        pre_index: true,
+        offset: Offset::Register {
+            shift: 0,
+            register: read_thumb_low_register(bits >> 6),
+        },
+    }
+}
+
+// Format 9: LDR/STR{B} Rd, [Rb, #Imm]
+fn decode_thumb_load_store_immediate_offset(bits: u32) -> InstructionOp {
+    let byte = (bits >> 12) & 0b1 != 0;
+    let offset = (bits >> 6) & 0b11111;
+
+    InstructionOp::SingleDataTransfer {
+        base: read_thumb_low_register(bits >> 3),
+        source_dest: read_thumb_low_register(bits),
+        load: (bits >> 11) & 0b1 != 0,
+        write_back: false,
+        write_byte: byte,
+        add_offset: true,
+        pre_index: true,
+        offset: Offset::Immediate {
+            // Word offsets are given in words
+            offset: if byte { offset } else { offset << 2 } as u16,
+        },
+    }
+}
+
+// Format 10: LDRH/STRH Rd, [Rb, #Imm]
+fn decode_thumb_load_store_halfword(bits: u32) -> InstructionOp {
+    InstructionOp::HalfwordDataTransfer {
+        base: read_thumb_low_register(bits >> 3),
+        source_dest: read_thumb_low_register(bits),
+        load: (bits >> 11) & 0b1 != 0,
+        write_back: false,
+        signed: false,
+        halfword: true,
+        add_offset: true,
+        pre_index: true,
+        offset: Offset::Immediate {
+            offset: (((bits >> 6) & 0b11111) << 1) as u16,
+        },
+    }
+}
+
+// Format 11: LDR/STR Rd, [SP, #Imm]
+fn decode_thumb_sp_relative_load_store(bits: u32) -> InstructionOp {
+    InstructionOp::SingleDataTransfer {
+        base: Register::R13,
+        source_dest: read_thumb_low_register(bits >> 8),
+        load: (bits >> 11) & 0b1 != 0,
+        write_back: false,
+        write_byte: false,
+        add_offset: true,
+        pre_index: true,
+        offset: Offset::Immediate {
+            offset: ((bits & 0b11111111) << 2) as u16,
+        },
+    }
+}
+
+// Format 12: ADD Rd, PC/SP, #Imm
+fn decode_thumb_load_address(bits: u32) -> InstructionOp {
+    let base = if (bits >> 11) & 0b1 != 0 {
+        Register::R13
+    } else {
+        Register::R15
+    };
+
+    InstructionOp::DataProcessing {
+        opcode: DataProcessingOpCode::Add,
+        dest: read_thumb_low_register(bits >> 8),
+        operand1: base,
+        // Word offset, an 8 bit value rotated right by 30 is a left shift by 2
+        operand2: Operand::Immediate {
+            rotate: 15,
+            value: (bits & 0b11111111) as u8,
+        },
+        alter_condition: false,
+    }
+}
+
+// Format 13: ADD SP, #+/-Imm
+fn decode_thumb_add_offset_to_sp(bits: u32) -> InstructionOp {
+    let opcode = if (bits >> 7) & 0b1 != 0 {
+        DataProcessingOpCode::Sub
+    } else {
+        DataProcessingOpCode::Add
+    };
+
+    InstructionOp::DataProcessing {
+        opcode,
+        dest: Register::R13,
+        operand1: Register::R13,
+        operand2: Operand::Immediate {
+            rotate: 15,
+            value: (bits & 0b1111111) as u8,
+        },
+        alter_condition: false,
+    }
+}
+
+// Format 14: PUSH {Rlist, LR} / POP {Rlist, PC}
+fn decode_thumb_push_pop(bits: u32) -> InstructionOp {
+    let load = (bits >> 11) & 0b1 != 0;
+
+    let mut register_list = read_thumb_register_list(bits);
+
+    if (bits >> 8) & 0b1 != 0 {
+        register_list.push(if load { Register::R15 } else { Register::R14 });
+    }
+
+    // PUSH is STMDB SP!, POP is LDMIA SP!
+    InstructionOp::BlockDataTransfer {
+        base: Register::R13,
+        load,
+        write_back: true,
+        force_psr: false,
+        add_offset: load,
+        pre_index: !load,
+        register_list,
+    }
+}
+
+// Format 15: STMIA/LDMIA Rb!, {Rlist}
+fn decode_thumb_multiple_load_store(bits: u32) -> InstructionOp {
+    InstructionOp::BlockDataTransfer {
+        base: read_thumb_low_register(bits >> 8),
+        load: (bits >> 11) & 0b1 != 0,
+        write_back: true,
+        force_psr: false,
+        add_offset: true,
+        pre_index: false,
+        register_list: read_thumb_register_list(bits),
+    }
+}
+
+// Format 16: B{cond} label
+fn decode_thumb_conditional_branch(bits: u32) -> InstructionOp {
+    InstructionOp::Branch {
+        branch: Branch::Offset {
+            offset: sign_extend_to_24(bits & 0b11111111, 8),
+            link: false,
+        },
+    }
+}
+
+// Format 18: B label
+fn decode_thumb_unconditional_branch(bits: u32) -> InstructionOp {
+    InstructionOp::Branch {
+        branch: Branch::Offset {
+            offset: sign_extend_to_24(bits & 0b111_11111111, 11),
+            link: false,
+        },
+    }
+}
+
+// Format 19: BL label, as a pair of instructions
+fn decode_thumb_long_branch_link(bits: u32) -> InstructionOp {
+    let offset = bits & 0b111_11111111;
+
+    let branch = if (bits >> 11) & 0b1 != 0 {
+        Branch::LongLinkLow { offset }
+    } else {
+        Branch::LongLinkHigh { offset }
+    };
+
+    InstructionOp::Branch { branch }
+}
+
+// Thumb branch offsets are stored in the same 24 bit two's complement field as arm branches
+fn sign_extend_to_24(value: u32, bits: u32) -> u32 {
+    if (value >> (bits - 1)) & 0b1 != 0 {
+        (value | (!0 << bits)) & 0b11111111_11111111_11111111
+    } else {
+        value
+    }
+}
+
+fn read_thumb_register_list(bits: u32) -> Vec<Register> {
+    (0..8)
+        .filter(|reg| bits & (0b1 << reg) != 0)
+        .map(read_register)
+        .collect()
+}
+
+fn read_thumb_low_register(bits: u32) -> Register {
+    read_register((bits & 0b111) as u8)
+}
+
 fn read_dataprocessing_opcode(bits: u8) -> DataProcessingOpCode {
     match bits {
         0b0000 => DataProcessingOpCode::And,
@@ -377,7 +966,7 @@ fn read_dataprocessing_opcode(bits: u8) -> DataProcessingOpCode {
     }
 }
 
-fn read_register(bits: u8) -> Register {
+pub(crate) fn read_register(bits: u8) -> Register {
     match bits {
         0b0000 => Register::R0,
         0b0001 => Register::R1,
@@ -643,4 +1232,669 @@ mod tests {
 
         assert_eq!(instr, expected);
     }
+
+    #[test]
+    fn test_register_shift_decode() {
+        let op = 0xe1a00231;
+
+        let instr = Instruction::decode_arm(op);
+
+        let expected = Instruction {
+            condition: Condition::Always,
+            instruction: InstructionOp::DataProcessing {
+                opcode: DataProcessingOpCode::Mov,
+                dest: Register::R0,
+                operand1: Register::R0,
+                operand2: Operand::Register {
+                    shift: 0x23,
+                    register: Register::R1,
+                },
+                alter_condition: false,
+            },
+        };
+
+        assert_eq!(instr, expected);
+    }
+
+    #[test]
+    fn test_never_condition_decode() {
+        let op = 0xf1a00000;
+
+        let instr = Instruction::decode_arm(op);
+
+        assert_eq!(instr.condition, Condition::Never);
+    }
+
+    #[test]
+    fn test_thumb_lsl_immediate_decode() {
+        let op = 0x0088;
+
+        let instr = Instruction::decode_thumb(op);
+
+        let expected = Instruction {
+            condition: Condition::Always,
+            instruction: InstructionOp::DataProcessing {
+                opcode: DataProcessingOpCode::Mov,
+                dest: Register::R0,
+                operand1: Register::R0,
+                operand2: Operand::Register {
+                    shift: 0b0001_0000,
+                    register: Register::R1,
+                },
+                alter_condition: true,
+            },
+        };
+
+        assert_eq!(instr, expected);
+    }
+
+    #[test]
+    fn test_thumb_add_register_decode() {
+        let op = 0x1888;
+
+        let instr = Instruction::decode_thumb(op);
+
+        let expected = Instruction {
+            condition: Condition::Always,
+            instruction: InstructionOp::DataProcessing {
+                opcode: DataProcessingOpCode::Add,
+                dest: Register::R0,
+                operand1: Register::R1,
+                operand2: Operand::Register {
+                    shift: 0,
+                    register: Register::R2,
+                },
+                alter_condition: true,
+            },
+        };
+
+        assert_eq!(instr, expected);
+    }
+
+    #[test]
+    fn test_thumb_mov_immediate_decode() {
+        let op = 0x2305;
+
+        let instr = Instruction::decode_thumb(op);
+
+        let expected = Instruction {
+            condition: Condition::Always,
+            instruction: InstructionOp::DataProcessing {
+                opcode: DataProcessingOpCode::Mov,
+                dest: Register::R3,
+                operand1: Register::R3,
+                operand2: Operand::Immediate {
+                    rotate: 0,
+                    value: 5,
+                },
+                alter_condition: true,
+            },
+        };
+
+        assert_eq!(instr, expected);
+    }
+
+    #[test]
+    fn test_thumb_alu_shift_decode() {
+        // lsls r1, r2
+        let op = 0x4091;
+
+        let instr = Instruction::decode_thumb(op);
+
+        let expected = Instruction {
+            condition: Condition::Always,
+            instruction: InstructionOp::DataProcessing {
+                opcode: DataProcessingOpCode::Mov,
+                dest: Register::R1,
+                operand1: Register::R0,
+                operand2: Operand::Register {
+                    shift: 0b0010_0001,
+                    register: Register::R1,
+                },
+                alter_condition: true,
+            },
+        };
+
+        assert_eq!(instr, expected);
+    }
+
+    #[test]
+    fn test_thumb_neg_decode() {
+        let op = 0x4248;
+
+        let instr = Instruction::decode_thumb(op);
+
+        let expected = Instruction {
+            condition: Condition::Always,
+            instruction: InstructionOp::DataProcessing {
+                opcode: DataProcessingOpCode::Rsb,
+                dest: Register::R0,
+                operand1: Register::R1,
+                operand2: Operand::Immediate {
+                    rotate: 0,
+                    value: 0,
+                },
+                alter_condition: true,
+            },
+        };
+
+        assert_eq!(instr, expected);
+    }
+
+    #[test]
+    fn test_thumb_mul_decode() {
+        let op = 0x4348;
+
+        let instr = Instruction::decode_thumb(op);
+
+        let expected = Instruction {
+            condition: Condition::Always,
+            instruction: InstructionOp::Multiply {
+                dest: Register::R0,
+                operand1: Register::R1,
+                operand2: Register::R0,
+                accumulate: false,
+                acc_operand: Register::R0,
+                alter_condition: true,
+            },
+        };
+
+        assert_eq!(instr, expected);
+    }
+
+    #[test]
+    fn test_thumb_hi_register_mov_decode() {
+        let op = 0x4688;
+
+        let instr = Instruction::decode_thumb(op);
+
+        let expected = Instruction {
+            condition: Condition::Always,
+            instruction: InstructionOp::DataProcessing {
+                opcode: DataProcessingOpCode::Mov,
+                dest: Register::R8,
+                operand1: Register::R8,
+                operand2: Operand::Register {
+                    shift: 0,
+                    register: Register::R1,
+                },
+                alter_condition: false,
+            },
+        };
+
+        assert_eq!(instr, expected);
+    }
+
+    #[test]
+    fn test_thumb_bx_decode() {
+        let op = 0x4770;
+
+        let instr = Instruction::decode_thumb(op);
+
+        let expected = Instruction {
+            condition: Condition::Always,
+            instruction: InstructionOp::Branch {
+                branch: Branch::Exchange {
+                    register: Register::R14,
+                },
+            },
+        };
+
+        assert_eq!(instr, expected);
+    }
+
+    #[test]
+    fn test_thumb_pc_relative_load_decode() {
+        let op = 0x4802;
+
+        let instr = Instruction::decode_thumb(op);
+
+        let expected = Instruction {
+            condition: Condition::Always,
+            instruction: InstructionOp::SingleDataTransfer {
+                base: Register::R15,
+                source_dest: Register::R0,
+                load: true,
+                write_back: false,
+                write_byte: false,
+                add_offset: true,
+                pre_index: true,
+                offset: Offset::Immediate { offset: 8 },
+            },
+        };
+
+        assert_eq!(instr, expected);
+    }
+
+    #[test]
+    fn test_thumb_str_immediate_decode() {
+        let op = 0x6041;
+
+        let instr = Instruction::decode_thumb(op);
+
+        let expected = Instruction {
+            condition: Condition::Always,
+            instruction: InstructionOp::SingleDataTransfer {
+                base: Register::R0,
+                source_dest: Register::R1,
+                load: false,
+                write_back: false,
+                write_byte: false,
+                add_offset: true,
+                pre_index: true,
+                offset: Offset::Immediate { offset: 4 },
+            },
+        };
+
+        assert_eq!(instr, expected);
+    }
+
+    #[test]
+    fn test_thumb_ldsb_decode() {
+        let op = 0x5688;
+
+        let instr = Instruction::decode_thumb(op);
+
+        let expected = Instruction {
+            condition: Condition::Always,
+            instruction: InstructionOp::HalfwordDataTransfer {
+                base: Register::R1,
+                source_dest: Register::R0,
+                load: true,
+                write_back: false,
+                signed: true,
+                halfword: false,
+                add_offset: true,
+                pre_index: true,
+                offset: Offset::Register {
+                    shift: 0,
+                    register: Register::R2,
+                },
+            },
+        };
+
+        assert_eq!(instr, expected);
+    }
+
+    #[test]
+    fn test_thumb_ldrh_immediate_decode() {
+        let op = 0x8848;
+
+        let instr = Instruction::decode_thumb(op);
+
+        let expected = Instruction {
+            condition: Condition::Always,
+            instruction: InstructionOp::HalfwordDataTransfer {
+                base: Register::R1,
+                source_dest: Register::R0,
+                load: true,
+                write_back: false,
+                signed: false,
+                halfword: true,
+                add_offset: true,
+                pre_index: true,
+                offset: Offset::Immediate { offset: 2 },
+            },
+        };
+
+        assert_eq!(instr, expected);
+    }
+
+    #[test]
+    fn test_thumb_sp_relative_load_decode() {
+        let op = 0x9801;
+
+        let instr = Instruction::decode_thumb(op);
+
+        let expected = Instruction {
+            condition: Condition::Always,
+            instruction: InstructionOp::SingleDataTransfer {
+                base: Register::R13,
+                source_dest: Register::R0,
+                load: true,
+                write_back: false,
+                write_byte: false,
+                add_offset: true,
+                pre_index: true,
+                offset: Offset::Immediate { offset: 4 },
+            },
+        };
+
+        assert_eq!(instr, expected);
+    }
+
+    #[test]
+    fn test_thumb_load_address_decode() {
+        // add r0, sp, #4
+        let op = 0xa801;
+
+        let instr = Instruction::decode_thumb(op);
+
+        let expected = Instruction {
+            condition: Condition::Always,
+            instruction: InstructionOp::DataProcessing {
+                opcode: DataProcessingOpCode::Add,
+                dest: Register::R0,
+                operand1: Register::R13,
+                operand2: Operand::Immediate {
+                    rotate: 15,
+                    value: 1,
+                },
+                alter_condition: false,
+            },
+        };
+
+        assert_eq!(instr, expected);
+    }
+
+    #[test]
+    fn test_thumb_sub_sp_decode() {
+        let op = 0xb082;
+
+        let instr = Instruction::decode_thumb(op);
+
+        let expected = Instruction {
+            condition: Condition::Always,
+            instruction: InstructionOp::DataProcessing {
+                opcode: DataProcessingOpCode::Sub,
+                dest: Register::R13,
+                operand1: Register::R13,
+                operand2: Operand::Immediate {
+                    rotate: 15,
+                    value: 2,
+                },
+                alter_condition: false,
+            },
+        };
+
+        assert_eq!(instr, expected);
+    }
+
+    #[test]
+    fn test_thumb_push_decode() {
+        let op = 0xb510;
+
+        let instr = Instruction::decode_thumb(op);
+
+        let expected = Instruction {
+            condition: Condition::Always,
+            instruction: InstructionOp::BlockDataTransfer {
+                base: Register::R13,
+                load: false,
+                write_back: true,
+                force_psr: false,
+                add_offset: false,
+                pre_index: true,
+                register_list: vec![Register::R4, Register::R14],
+            },
+        };
+
+        assert_eq!(instr, expected);
+    }
+
+    #[test]
+    fn test_thumb_pop_decode() {
+        let op = 0xbd10;
+
+        let instr = Instruction::decode_thumb(op);
+
+        let expected = Instruction {
+            condition: Condition::Always,
+            instruction: InstructionOp::BlockDataTransfer {
+                base: Register::R13,
+                load: true,
+                write_back: true,
+                force_psr: false,
+                add_offset: true,
+                pre_index: false,
+                register_list: vec![Register::R4, Register::R15],
+            },
+        };
+
+        assert_eq!(instr, expected);
+    }
+
+    #[test]
+    fn test_thumb_ldmia_decode() {
+        let op = 0xc806;
+
+        let instr = Instruction::decode_thumb(op);
+
+        let expected = Instruction {
+            condition: Condition::Always,
+            instruction: InstructionOp::BlockDataTransfer {
+                base: Register::R0,
+                load: true,
+                write_back: true,
+                force_psr: false,
+                add_offset: true,
+                pre_index: false,
+                register_list: vec![Register::R1, Register::R2],
+            },
+        };
+
+        assert_eq!(instr, expected);
+    }
+
+    #[test]
+    fn test_thumb_conditional_branch_decode() {
+        let op = 0xd0fe;
+
+        let instr = Instruction::decode_thumb(op);
+
+        let expected = Instruction {
+            condition: Condition::Equal,
+            instruction: InstructionOp::Branch {
+                branch: Branch::Offset {
+                    offset: 0xfffffe,
+                    link: false,
+                },
+            },
+        };
+
+        assert_eq!(instr, expected);
+    }
+
+    #[test]
+    fn test_thumb_swi_decode() {
+        let op = 0xdf06;
+
+        let instr = Instruction::decode_thumb(op);
+
+        let expected = Instruction {
+            condition: Condition::Always,
+            instruction: InstructionOp::SoftwareInterrupt { comment: 6 },
+        };
+
+        assert_eq!(instr, expected);
+    }
+
+    #[test]
+    fn test_thumb_unconditional_branch_decode() {
+        let op = 0xe7fe;
+
+        let instr = Instruction::decode_thumb(op);
+
+        let expected = Instruction {
+            condition: Condition::Always,
+            instruction: InstructionOp::Branch {
+                branch: Branch::Offset {
+                    offset: 0xfffffe,
+                    link: false,
+                },
+            },
+        };
+
+        assert_eq!(instr, expected);
+    }
+
+    #[test]
+    fn test_thumb_long_branch_link_decode() {
+        let high = Instruction::decode_thumb(0xf7ff);
+        let low = Instruction::decode_thumb(0xfffe);
+
+        assert_eq!(
+            high.instruction,
+            InstructionOp::Branch {
+                branch: Branch::LongLinkHigh { offset: 0x7ff }
+            }
+        );
+        assert_eq!(
+            low.instruction,
+            InstructionOp::Branch {
+                branch: Branch::LongLinkLow { offset: 0x7fe }
+            }
+        );
+    }
+
+    #[test]
+    fn test_ldrh_immediate_decode() {
+        // ldrh r0, [r1, #0x12]
+        let op = 0xe1d101b2;
+
+        let instr = Instruction::decode_arm(op);
+
+        let expected = Instruction {
+            condition: Condition::Always,
+            instruction: InstructionOp::HalfwordDataTransfer {
+                base: Register::R1,
+                source_dest: Register::R0,
+                load: true,
+                write_back: false,
+                signed: false,
+                halfword: true,
+                add_offset: true,
+                pre_index: true,
+                offset: Offset::Immediate { offset: 0x12 },
+            },
+        };
+
+        assert_eq!(instr, expected);
+    }
+
+    #[test]
+    fn test_ldrsb_register_decode() {
+        // ldrsb r3, [r4], -r5
+        let op = 0xe01430d5;
+
+        let instr = Instruction::decode_arm(op);
+
+        let expected = Instruction {
+            condition: Condition::Always,
+            instruction: InstructionOp::HalfwordDataTransfer {
+                base: Register::R4,
+                source_dest: Register::R3,
+                load: true,
+                write_back: false,
+                signed: true,
+                halfword: false,
+                add_offset: false,
+                pre_index: false,
+                offset: Offset::Register {
+                    shift: 0,
+                    register: Register::R5,
+                },
+            },
+        };
+
+        assert_eq!(instr, expected);
+    }
+
+    #[test]
+    fn test_mrs_decode() {
+        let op = 0xe14f0000;
+
+        let instr = Instruction::decode_arm(op);
+
+        let expected = Instruction {
+            condition: Condition::Always,
+            instruction: InstructionOp::MoveFromPsr {
+                dest: Register::R0,
+                spsr: true,
+            },
+        };
+
+        assert_eq!(instr, expected);
+    }
+
+    #[test]
+    fn test_msr_register_decode() {
+        // msr cpsr_fc, r0
+        let op = 0xe129f000;
+
+        let instr = Instruction::decode_arm(op);
+
+        let expected = Instruction {
+            condition: Condition::Always,
+            instruction: InstructionOp::MoveToPsr {
+                source: Operand::Register {
+                    shift: 0,
+                    register: Register::R0,
+                },
+                spsr: false,
+                fields: 0b1001,
+            },
+        };
+
+        assert_eq!(instr, expected);
+    }
+
+    #[test]
+    fn test_msr_immediate_flags_decode() {
+        // msr cpsr_f, #0xf0000000
+        let op = 0xe328f20f;
+
+        let instr = Instruction::decode_arm(op);
+
+        let expected = Instruction {
+            condition: Condition::Always,
+            instruction: InstructionOp::MoveToPsr {
+                source: Operand::Immediate {
+                    rotate: 2,
+                    value: 0xf,
+                },
+                spsr: false,
+                fields: 0b1000,
+            },
+        };
+
+        assert_eq!(instr, expected);
+    }
+
+    #[test]
+    fn test_swi_decode() {
+        let op = 0xef060000;
+
+        let instr = Instruction::decode_arm(op);
+
+        let expected = Instruction {
+            condition: Condition::Always,
+            instruction: InstructionOp::SoftwareInterrupt { comment: 0x060000 },
+        };
+
+        assert_eq!(instr, expected);
+    }
+
+    #[test]
+    fn test_undefined_decode() {
+        assert_eq!(
+            InstructionOp::Undefined,
+            Instruction::decode_arm(0xe7f000f0).instruction
+        );
+
+        // Coprocessor data operation
+        assert_eq!(
+            InstructionOp::Undefined,
+            Instruction::decode_arm(0xee000000).instruction
+        );
+
+        assert_eq!(
+            InstructionOp::Undefined,
+            Instruction::decode_thumb(0xde00).instruction
+        );
+        assert_eq!(
+            InstructionOp::Undefined,
+            Instruction::decode_thumb(0xe800).instruction
+        );
+    }
 }
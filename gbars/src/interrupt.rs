@@ -0,0 +1,55 @@
+// Interrupt sources, in the bit order of IE and IF
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Interrupt {
+    VBlank,
+    HBlank,
+    VCount,
+    Timer0,
+    Timer1,
+    Timer2,
+    Timer3,
+    Serial,
+    Dma0,
+    Dma1,
+    Dma2,
+    Dma3,
+    Keypad,
+    GamePak,
+}
+
+impl Interrupt {
+    pub fn bit(self) -> u16 {
+        0b1 << (self as u16)
+    }
+
+    pub fn timer(n: usize) -> Interrupt {
+        match n {
+            0 => Interrupt::Timer0,
+            1 => Interrupt::Timer1,
+            2 => Interrupt::Timer2,
+            _ => Interrupt::Timer3,
+        }
+    }
+
+    pub fn dma(n: usize) -> Interrupt {
+        match n {
+            0 => Interrupt::Dma0,
+            1 => Interrupt::Dma1,
+            2 => Interrupt::Dma2,
+            _ => Interrupt::Dma3,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_interrupt_bits() {
+        assert_eq!(0b1, Interrupt::VBlank.bit());
+        assert_eq!(0b1000, Interrupt::timer(0).bit());
+        assert_eq!(0b1 << 11, Interrupt::dma(3).bit());
+        assert_eq!(0b1 << 13, Interrupt::GamePak.bit());
+    }
+}
@@ -0,0 +1,343 @@
+use log::trace;
+
+// Offsets from 0x04000000 of the registers the rest of the system looks at
+pub const DISPCNT: u32 = 0x000;
+pub const DISPSTAT: u32 = 0x004;
+pub const VCOUNT: u32 = 0x006;
+pub const KEYINPUT: u32 = 0x130;
+pub const SOUNDCNT_H: u32 = 0x082;
+pub const SOUNDCNT_X: u32 = 0x084;
+pub const SOUNDBIAS: u32 = 0x088;
+pub const FIFO_A: u32 = 0x0A0;
+pub const FIFO_B: u32 = 0x0A4;
+pub const DMA0SAD: u32 = 0x0B0;
+pub const DMA0CNT_H: u32 = 0x0BA;
+pub const TM0CNT_L: u32 = 0x100;
+pub const TM0CNT_H: u32 = 0x102;
+pub const SIODATA32: u32 = 0x120;
+pub const SIOCNT: u32 = 0x128;
+pub const SIODATA8: u32 = 0x12A;
+pub const IE: u32 = 0x200;
+pub const IF: u32 = 0x202;
+pub const WAITCNT: u32 = 0x204;
+pub const IME: u32 = 0x208;
+pub const POSTFLG_HALTCNT: u32 = 0x300;
+
+// Which bits of a halfword register can be read and written.
+// Write-only registers have a read mask of 0 and read back as zero
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+struct RegisterInfo {
+    read_mask: u16,
+    write_mask: u16,
+}
+
+const fn rw(mask: u16) -> RegisterInfo {
+    RegisterInfo {
+        read_mask: mask,
+        write_mask: mask,
+    }
+}
+
+const fn masks(read_mask: u16, write_mask: u16) -> RegisterInfo {
+    RegisterInfo {
+        read_mask,
+        write_mask,
+    }
+}
+
+const fn write_only(mask: u16) -> RegisterInfo {
+    masks(0, mask)
+}
+
+// Unused halfwords inside a register block, which read as zero rather than open bus
+const ZERO: RegisterInfo = masks(0, 0);
+
+// None for addresses with no register, which read as open bus
+fn register_info(offset: u32) -> Option<RegisterInfo> {
+    let info = match offset {
+        // LCD
+        DISPCNT => masks(0xFFFF, 0xFFF7), // bit 3 is set by the BIOS only
+        0x002 => rw(0x0001),              // Green swap
+        DISPSTAT => masks(0xFF3F, 0xFF38), // status flags are read-only
+        VCOUNT => masks(0x00FF, 0),
+        0x008 | 0x00A => rw(0xDFFF),         // BG0CNT, BG1CNT
+        0x00C | 0x00E => rw(0xFFFF),         // BG2CNT, BG3CNT
+        0x010..=0x01E => write_only(0x01FF), // BGxHOFS, BGxVOFS
+        0x020..=0x03E => write_only(0xFFFF), // BG2/3 affine parameters and reference points
+        0x040..=0x046 => write_only(0xFFFF), // WIN0H, WIN1H, WIN0V, WIN1V
+        0x048 | 0x04A => rw(0x3F3F),         // WININ, WINOUT
+        0x04C => write_only(0xFFFF),         // MOSAIC
+        0x04E => ZERO,
+        0x050 => rw(0x3FFF),         // BLDCNT
+        0x052 => rw(0x1F1F),         // BLDALPHA
+        0x054 => write_only(0x001F), // BLDY
+        0x056..=0x05E => ZERO,
+
+        // Sound
+        0x060 => rw(0x007F),            // SOUND1CNT_L
+        0x062 => masks(0xFFC0, 0xFFFF), // SOUND1CNT_H, length is write-only
+        0x064 => masks(0x4000, 0xC7FF), // SOUND1CNT_X, frequency is write-only
+        0x068 => masks(0xFFC0, 0xFFFF), // SOUND2CNT_L
+        0x06C => masks(0x4000, 0xC7FF), // SOUND2CNT_H
+        0x070 => rw(0x00E0),            // SOUND3CNT_L
+        0x072 => masks(0xE000, 0xE0FF), // SOUND3CNT_H
+        0x074 => masks(0x4000, 0xC7FF), // SOUND3CNT_X
+        0x078 => masks(0xFF00, 0xFF3F), // SOUND4CNT_L
+        0x07C => masks(0x40FF, 0xC0FF), // SOUND4CNT_H
+        0x066 | 0x06A | 0x06E | 0x076 | 0x07A | 0x07E => ZERO,
+        0x080 => rw(0xFF77),                 // SOUNDCNT_L
+        SOUNDCNT_H => masks(0x770F, 0xFF0F), // FIFO resets are write-only
+        SOUNDCNT_X => masks(0x008F, 0x0080), // channel status is read-only
+        0x086 | 0x08A => ZERO,
+        SOUNDBIAS => rw(0xC3FE),
+        0x090..=0x09E => rw(0xFFFF),          // WAVE_RAM
+        FIFO_A..=0x0A6 => write_only(0xFFFF), // FIFO_A, FIFO_B
+
+        // DMA, 12 bytes per channel
+        0x0B0..=0x0DE => match (offset - 0x0B0) % 12 {
+            0..=6 => write_only(0xFFFF),        // DMAxSAD, DMAxDAD
+            8 => write_only(0xFFFF),            // DMAxCNT_L
+            _ if offset == 0x0DE => rw(0xFFE0), // DMA3CNT_H, with game pak DRQ
+            _ => rw(0xF7E0),                    // DMAxCNT_H
+        },
+
+        // Timers
+        0x100 | 0x104 | 0x108 | 0x10C => rw(0xFFFF), // TMxCNT_L, which reads the counter
+        TM0CNT_H => rw(0x00C3),                      // timer 0 can't count up
+        0x106 | 0x10A | 0x10E => rw(0x00C7),         // TMxCNT_H
+
+        // Serial and keypad
+        0x120..=0x12A => rw(0xFFFF), // SIOMULTI0-3, SIOCNT, SIODATA8
+        KEYINPUT => masks(0x03FF, 0),
+        0x132 => rw(0xC3FF), // KEYCNT
+        0x134 => rw(0xC1FF), // RCNT
+        0x136 => ZERO,
+        0x140 => rw(0x0047),            // JOYCNT
+        0x150..=0x156 => rw(0xFFFF),    // JOY_RECV, JOY_TRANS
+        0x158 => masks(0x003A, 0x0030), // JOYSTAT
+
+        // Interrupts and system control
+        IE => rw(0x3FFF),
+        IF => rw(0x3FFF),      // writing 1 acknowledges
+        WAITCNT => rw(0x5FFF), // bit 15 reads the cartridge type
+        IME => rw(0x0001),
+        0x206 | 0x20A => ZERO,
+        POSTFLG_HALTCNT => masks(0x0001, 0x8001), // HALTCNT is write-only in the top byte
+        _ => return None,
+    };
+
+    Some(info)
+}
+
+// A register write the owning peripheral has to act on
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum IoEvent {
+    Interrupt,    // IE, IF or IME changed
+    Serial,       // SIOCNT's start bit was set
+    SoundControl, // SOUNDCNT_H or SOUNDCNT_X was written
+    SoundFifo {
+        channel: usize,
+        data: u16,
+        lanes: u16,
+    },
+    Dma(usize),   // DMAxCNT_H was written
+    Timer(usize), // TMxCNT_H was written
+    Waitcnt,
+    Halt,
+    Stop,
+}
+
+pub struct Io {
+    registers: Vec<u16>, // Raw values, indexed by halfword offset
+}
+
+impl Io {
+    pub fn new() -> Io {
+        let mut io = Io {
+            registers: vec![0; 0x200],
+        };
+
+        // No keys held
+        io.set_register(KEYINPUT, 0x03FF);
+        io.set_register(SOUNDBIAS, 0x0200);
+
+        io
+    }
+
+    // Registers by their offset from 0x04000000, without any masking
+    pub fn register(&self, offset: u32) -> u16 {
+        self.registers[(offset as usize & 0x3FF) / 2]
+    }
+
+    // For peripherals updating their read-only state
+    pub fn set_register(&mut self, offset: u32, val: u16) {
+        self.registers[(offset as usize & 0x3FF) / 2] = val;
+    }
+
+    pub fn is_unused(&self, addr: u32) -> bool {
+        register_info(addr & 0x3FE).is_none()
+    }
+
+    pub fn read_halfword(&self, addr: u32) -> Option<u16> {
+        let offset = addr & 0x3FE;
+
+        register_info(offset).map(|info| self.register(offset) & info.read_mask)
+    }
+
+    pub fn read_byte(&self, addr: u32) -> Option<u8> {
+        self.read_halfword(addr)
+            .map(|val| (val >> (8 * (addr & 0b1))) as u8)
+    }
+
+    pub fn write_halfword(&mut self, addr: u32, val: u16) -> Option<IoEvent> {
+        self.write(addr & 0x3FE, val, 0xFFFF)
+    }
+
+    // Only the addressed byte of the register is written, the other keeps its value
+    pub fn write_byte(&mut self, addr: u32, val: u8) -> Option<IoEvent> {
+        let shift = 8 * (addr & 0b1);
+
+        self.write(addr & 0x3FE, (val as u16) << shift, 0xFF << shift)
+    }
+
+    fn write(&mut self, offset: u32, val: u16, lanes: u16) -> Option<IoEvent> {
+        let info = match register_info(offset) {
+            Some(info) => info,
+            None => {
+                trace!("Ignoring write to unused io {:3x}", offset);
+                return None;
+            }
+        };
+
+        let mask = info.write_mask & lanes;
+        let old = self.register(offset);
+
+        if offset == IF {
+            // Interrupts are acknowledged by writing 1 to their flag
+            self.set_register(offset, old & !(val & mask));
+            return Some(IoEvent::Interrupt);
+        }
+
+        self.set_register(offset, (old & !mask) | (val & mask));
+
+        match offset {
+            IE | IME => Some(IoEvent::Interrupt),
+            SOUNDCNT_H | SOUNDCNT_X => Some(IoEvent::SoundControl),
+            FIFO_A..=0x0A6 => Some(IoEvent::SoundFifo {
+                channel: if offset < FIFO_B { 0 } else { 1 },
+                data: val & mask,
+                lanes: mask,
+            }),
+            0x0BA | 0x0C6 | 0x0D2 | 0x0DE => {
+                Some(IoEvent::Dma(((offset - DMA0CNT_H) / 12) as usize))
+            }
+            0x102 | 0x106 | 0x10A | 0x10E => {
+                Some(IoEvent::Timer(((offset - TM0CNT_H) / 4) as usize))
+            }
+            SIOCNT if old & 0x80 == 0 && val & mask & 0x80 != 0 => Some(IoEvent::Serial),
+            WAITCNT => Some(IoEvent::Waitcnt),
+            POSTFLG_HALTCNT if lanes & 0xFF00 != 0 => {
+                if val & 0x8000 != 0 {
+                    Some(IoEvent::Stop)
+                } else {
+                    Some(IoEvent::Halt)
+                }
+            }
+            _ => None,
+        }
+    }
+}
+
+impl Default for Io {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_register_masks() {
+        let mut io = Io::new();
+
+        // DISPSTAT's status bits and VCOUNT can't be written
+        io.write_halfword(0x004, 0xFFFF);
+        io.write_halfword(0x006, 0xFFFF);
+        assert_eq!(Some(0xFF38), io.read_halfword(0x004));
+        assert_eq!(Some(0), io.read_halfword(0x006));
+
+        // Scroll registers are write-only
+        io.write_halfword(0x010, 0x1234);
+        assert_eq!(Some(0), io.read_halfword(0x010));
+        assert_eq!(0x0034, io.register(0x010) & 0x00FF);
+
+        assert_eq!(Some(0x03FF), io.read_halfword(KEYINPUT));
+    }
+
+    #[test]
+    fn test_unused_registers() {
+        let io = Io::new();
+
+        assert_eq!(None, io.read_halfword(0x0E0));
+        assert_eq!(None, io.read_byte(0x20C));
+        assert_eq!(Some(0), io.read_halfword(0x206));
+        assert!(io.is_unused(0x3FE));
+    }
+
+    #[test]
+    fn test_byte_writes() {
+        let mut io = Io::new();
+
+        io.write_halfword(0x050, 0x1234);
+        io.write_byte(0x051, 0x3f);
+
+        assert_eq!(Some(0x3f34), io.read_halfword(0x050));
+        assert_eq!(Some(0x3f), io.read_byte(0x051));
+    }
+
+    #[test]
+    fn test_interrupt_acknowledge() {
+        let mut io = Io::new();
+        io.set_register(IF, 0b1011);
+
+        io.write_halfword(IF, 0b0010);
+        assert_eq!(Some(0b1001), io.read_halfword(IF));
+
+        // A byte write only acknowledges its own byte
+        io.set_register(IF, 0x0101);
+        io.write_byte(IF + 1, 0x01);
+        assert_eq!(Some(0x0001), io.read_halfword(IF));
+    }
+
+    #[test]
+    fn test_write_events() {
+        let mut io = Io::new();
+
+        assert_eq!(Some(IoEvent::Waitcnt), io.write_halfword(WAITCNT, 0x4317));
+        assert_eq!(Some(IoEvent::Interrupt), io.write_halfword(IF, 0x0001));
+        assert_eq!(Some(IoEvent::Serial), io.write_halfword(SIOCNT, 0x0081));
+        assert_eq!(None, io.write_halfword(SIOCNT, 0x0081));
+        assert_eq!(Some(IoEvent::Timer(2)), io.write_halfword(0x10A, 0x0080));
+        assert_eq!(Some(IoEvent::Dma(3)), io.write_halfword(0x0DE, 0x8000));
+        assert_eq!(
+            Some(IoEvent::SoundFifo {
+                channel: 1,
+                data: 0x1200,
+                lanes: 0xFF00
+            }),
+            io.write_byte(FIFO_B + 3, 0x12)
+        );
+        assert_eq!(None, io.write_byte(POSTFLG_HALTCNT, 0x01));
+        assert_eq!(
+            Some(IoEvent::Halt),
+            io.write_byte(POSTFLG_HALTCNT + 1, 0x00)
+        );
+        assert_eq!(
+            Some(IoEvent::Stop),
+            io.write_byte(POSTFLG_HALTCNT + 1, 0x80)
+        );
+        assert_eq!(Some(1), io.read_halfword(POSTFLG_HALTCNT));
+    }
+}
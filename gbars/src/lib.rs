@@ -1,7 +1,20 @@
+mod bios;
+mod bus;
 mod cpu;
+mod dma;
 mod instruction;
+mod interrupt;
+mod io;
 mod memory;
+mod scheduler;
+mod sound;
+mod system;
+mod timer;
 mod execute;
+mod shifter;
 
-pub use cpu::Cpu;
-pub use memory::Memory;
\ No newline at end of file
+pub use bus::{Access, AccessKind, Bus};
+pub use cpu::{Cpu, Exception, Mode};
+pub use interrupt::Interrupt;
+pub use memory::{DataAccess, Memory, Region};
+pub use system::System;
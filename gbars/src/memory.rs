@@ -1,58 +1,1780 @@
-use log::trace;
+use crate::bios;
+use crate::bus::{Access, AccessKind, Bus};
+use crate::dma::{Dma, Timing};
+use crate::interrupt::Interrupt;
+use crate::io::{self, Io, IoEvent};
+use crate::scheduler::{Event, Scheduler};
+use crate::sound::DirectSound;
+use crate::system::System;
+use crate::timer::Timer;
+
+use log::{debug, trace};
+
+// Wait states selected by each WAITCNT field
+const FIRST_ACCESS_WAITS: [u32; 4] = [4, 3, 2, 8];
+const SECOND_ACCESS_WAITS: [[u32; 2]; 3] = [[2, 1], [4, 1], [8, 1]]; // per ROM mirror
+const WAITCNT_PREFETCH: u16 = 0b1 << 14;
+
+const PREFETCH_CAPACITY: u32 = 8; // halfwords
+const ROM_END: u32 = 0x0DFFFFFE; // The last halfword the buffer can read ahead
+
+// What the BIOS has just fetched once it has booted the cartridge, and on leaving a SWI
+const BIOS_OPCODE_AFTER_BOOT: u32 = 0xE129F000;
+
+// Cycles between an enabled interrupt being flagged and the cpu seeing its IRQ line go high
+const IRQ_DELAY: u64 = 3;
+
+// Each scanline is drawn then has a horizontal blank, and after 160 lines there are 68 lines of
+// vertical blank
+const HDRAW_CYCLES: u64 = 1006;
+const SCANLINE_CYCLES: u64 = 1232;
+const VISIBLE_LINES: u16 = 160;
+const TOTAL_LINES: u16 = 228;
+
+// DISPSTAT
+const DISPSTAT_VBLANK: u16 = 1 << 0;
+const DISPSTAT_HBLANK: u16 = 1 << 1;
+const DISPSTAT_VCOUNT_MATCH: u16 = 1 << 2;
+const DISPSTAT_VBLANK_IRQ: u16 = 1 << 3;
+const DISPSTAT_HBLANK_IRQ: u16 = 1 << 4;
+const DISPSTAT_VCOUNT_IRQ: u16 = 1 << 5;
+
+// SIOCNT
+const SIOCNT_INTERNAL_CLOCK: u16 = 1 << 0;
+const SIOCNT_2MHZ: u16 = 1 << 1;
+const SIOCNT_START: u16 = 1 << 7;
+const SIOCNT_32BIT: u16 = 1 << 12;
+const SIOCNT_IRQ: u16 = 1 << 14;
+
+// SOUNDCNT_H
+const SOUNDCNT_FIFO_A_TIMER1: u16 = 1 << 10;
+const SOUNDCNT_FIFO_A_RESET: u16 = 1 << 11;
+const SOUNDCNT_FIFO_B_TIMER1: u16 = 1 << 14;
+const SOUNDCNT_FIFO_B_RESET: u16 = 1 << 15;
+
+// SOUNDCNT_X
+const SOUNDCNT_MASTER_ENABLE: u16 = 1 << 7;
+
+// The sound output is sampled at 32768Hz
+const SAMPLE_CYCLES: u64 = 512;
+
+// Cycles between a DMA channel being turned on and it starting an immediate transfer
+const DMA_START_DELAY: u64 = 2;
+
+// Stop turns off the clocks, so only interrupts that come from outside can end it
+const STOP_WAKE_INTERRUPTS: u16 = 0b11_0000_1000_0000; // Serial, Keypad, GamePak
+
+// Low power states entered by writing HALTCNT
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+enum Sleep {
+    Awake,
+    Halt, // Until any interrupt enabled in IE is flagged
+    Stop, // Until a serial, keypad or game pak interrupt
+}
+
+// The areas of the address space, decoded from the top byte of an address
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Region {
+    Bios,
+    Ewram,
+    Iwram,
+    Io,
+    Palette,
+    Vram,
+    Oam,
+    Rom,
+    Sram,
+    Unmapped,
+}
+
+impl Region {
+    pub fn from_address(addr: u32) -> Region {
+        match addr >> 24 {
+            0x00 if addr < 0x4000 => Region::Bios,
+            0x02 => Region::Ewram,
+            0x03 => Region::Iwram,
+            0x04 if addr < 0x04000400 => Region::Io,
+            0x05 => Region::Palette,
+            0x06 => Region::Vram,
+            0x07 => Region::Oam,
+            0x08..=0x0D => Region::Rom, // Three mirrors, one per wait state setting
+            0x0E | 0x0F => Region::Sram,
+            _ => Region::Unmapped,
+        }
+    }
+
+    // Width in bits of the data bus to the region
+    pub fn bus_width(self) -> u32 {
+        match self {
+            Region::Bios | Region::Iwram | Region::Io | Region::Oam => 32,
+            Region::Ewram | Region::Palette | Region::Vram | Region::Rom => 16,
+            Region::Sram | Region::Unmapped => 8,
+        }
+    }
+}
+
+// The GamePak prefetch buffer reads ahead of the cpu's opcode fetches while the ROM bus is free
+#[derive(Debug, Default)]
+struct Prefetch {
+    active: bool,
+    head: u32, // Address of the oldest buffered halfword, or of the one being fetched if empty
+    count: u32, // Buffered halfwords
+    countdown: u32, // Cycles until the halfword being fetched arrives
+    fetch_cycles: u32, // Cycles per halfword read ahead, from the wait states of head's mirror
+}
+
+impl Prefetch {
+    fn restart(&mut self, addr: u32, fetch_cycles: u32) {
+        self.active = addr <= ROM_END;
+        self.head = addr;
+        self.count = 0;
+        self.countdown = fetch_cycles;
+        self.fetch_cycles = fetch_cycles;
+    }
+
+    fn stop(&mut self) {
+        self.active = false;
+        self.count = 0;
+    }
+
+    // The ROM bus was free for cycles, so the buffer reads ahead. It stops at the end of ROM
+    fn advance(&mut self, cycles: u32) {
+        let mut cycles = cycles;
+
+        while self.active
+            && self.count < PREFETCH_CAPACITY
+            && self.head + 2 * self.count <= ROM_END
+            && cycles > 0
+        {
+            if cycles < self.countdown {
+                self.countdown -= cycles;
+                return;
+            }
+
+            cycles -= self.countdown;
+            self.count += 1;
+            self.countdown = self.fetch_cycles;
+        }
+    }
+
+    // Cycles to deliver the halfword at addr, or None if it isn't buffered or on its way
+    fn take(&mut self, addr: u32) -> Option<u32> {
+        if !self.active || addr != self.head {
+            return None;
+        }
+
+        self.head += 2;
+        self.active = self.head <= ROM_END;
+
+        if self.count > 0 {
+            // The buffer keeps reading ahead during the cycle it hands over a halfword
+            self.count -= 1;
+            self.advance(1);
+
+            Some(1)
+        } else {
+            // Wait for the fetch in progress, the next one starts straight after
+            let remaining = self.countdown;
+            self.countdown = self.fetch_cycles;
+
+            Some(remaining)
+        }
+    }
+}
+
+// A data access through the bus, as a watchpoint sees it
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct DataAccess {
+    pub addr: u32,
+    pub width: u32,
+    pub write: bool,
+    pub locked: bool, // Part of an atomic SWP
+}
 
 pub struct Memory {
-    //todo actual memory
     bios: Vec<u8>,         // 16kb
     onboard_wram: Vec<u8>, // 256kb
     onchip_wram: Vec<u8>,  // 32kb
+    io: Io,                // 1kb of registers
+    palette: Vec<u8>,      // 1kb
+    vram: Vec<u8>,         // 96kb
+    oam: Vec<u8>,          // 1kb
     rom: Vec<u8>,          // 32mb
+    sram: Vec<u8>,         // 64kb
+
+    locked: bool, // Set for the duration of an atomic SWP
+    watcher: Option<Box<dyn FnMut(DataAccess)>>,
+
+    cycles: u64, // Clock cycles since power on, advanced by every bus access
+
+    prefetch: Prefetch,
+    prefetch_emulation: bool, // Off ignores WAITCNT's prefetch enable, to compare timings
+
+    // Unmapped reads see the opcodes the cpu fetched last
+    open_bus: u32,
+    last_opcode_halfword: u16,
+
+    // The BIOS can only be read by code running from it, anything else sees its last fetch
+    bios_opcode: u32,
+    executing_bios: bool,
+
+    irq_since: Option<u64>, // When IME && (IE & IF) last became true
+    sleep: Sleep,
+
+    scheduler: Scheduler,
+    timers: [Timer; 4],
+    direct_sound: DirectSound,
+    sampling: bool, // An AudioSample event is pending
+    dma: [Dma; 4],
 }
 
 impl Memory {
     pub fn new() -> Memory {
+        Memory::new_with_bios_and_rom(vec![0; 0x4000], vec![0; 0x2000000])
+    }
+
+    pub fn new_with_bios_and_rom(bios: Vec<u8>, rom: Vec<u8>) -> Memory {
+        let mut scheduler = Scheduler::default();
+        scheduler.schedule(HDRAW_CYCLES, Event::HBlank);
+        scheduler.schedule(SCANLINE_CYCLES, Event::HDraw);
+
         Memory {
-            bios: vec![0; 0x4000],
+            bios,
             onboard_wram: vec![0; 0x40000],
             onchip_wram: vec![0; 0x8000],
-            rom: vec![0; 0x2000000],
+            io: Io::new(),
+            palette: vec![0; 0x400],
+            vram: vec![0; 0x18000],
+            oam: vec![0; 0x400],
+            rom,
+            sram: vec![0; 0x10000],
+            locked: false,
+            watcher: None,
+            cycles: 0,
+            prefetch: Prefetch::default(),
+            prefetch_emulation: true,
+            open_bus: 0,
+            last_opcode_halfword: 0,
+            bios_opcode: 0,
+            executing_bios: true,
+            irq_since: None,
+            sleep: Sleep::Awake,
+            scheduler,
+            timers: [Timer::default(); 4],
+            direct_sound: DirectSound::default(),
+            sampling: false,
+            dma: [Dma::default(); 4],
         }
     }
 
-    pub fn new_with_bios_and_rom(bios: Vec<u8>, rom: Vec<u8>) -> Memory {
+    // No BIOS image, just enough of one for the cpu's emulated BIOS calls and interrupt handling
+    pub fn new_with_rom(rom: Vec<u8>) -> Memory {
         Memory {
-            bios: bios,
-            onboard_wram: vec![0; 0x40000],
-            onchip_wram: vec![0; 0x8000],
-            rom: rom,
+            bios_opcode: BIOS_OPCODE_AFTER_BOOT,
+            executing_bios: false,
+            ..Memory::new_with_bios_and_rom(bios::hle_bios_image(), rom)
         }
     }
 
-    pub fn get_byte(&self, addr: u32) -> u8 {
-        match addr {
-            0x08000000..=0x08FFFFFF => {
-                let offset = addr as usize - 0x08000000;
-                if offset < self.rom.len() {
-                    self.rom[offset]
+    // Offset into the region's backing store, after applying its mirroring
+    fn mirrored_offset(region: Region, addr: u32) -> usize {
+        let offset = match region {
+            Region::Bios => addr,
+            Region::Ewram => addr & 0x3FFFF,
+            Region::Iwram => addr & 0x7FFF,
+            Region::Io => addr & 0x3FF,
+            Region::Palette | Region::Oam => addr & 0x3FF,
+            Region::Vram => {
+                // 128kb mirrors, with the upper 32kb repeating the last 32kb of the 96kb
+                let offset = addr & 0x1FFFF;
+                if offset >= 0x18000 {
+                    offset - 0x8000
+                } else {
+                    offset
                 }
-                else {
-                    0
+            }
+            Region::Rom => addr & 0x1FFFFFF,
+            Region::Sram => addr & 0xFFFF,
+            Region::Unmapped => 0,
+        };
+
+        offset as usize
+    }
+
+    fn backing(&self, region: Region) -> &[u8] {
+        match region {
+            Region::Bios => &self.bios,
+            Region::Ewram => &self.onboard_wram,
+            Region::Iwram => &self.onchip_wram,
+            Region::Palette => &self.palette,
+            Region::Vram => &self.vram,
+            Region::Oam => &self.oam,
+            Region::Rom => &self.rom,
+            Region::Sram => &self.sram,
+            Region::Io | Region::Unmapped => &[],
+        }
+    }
+
+    fn backing_mut(&mut self, region: Region) -> Option<&mut Vec<u8>> {
+        match region {
+            Region::Ewram => Some(&mut self.onboard_wram),
+            Region::Iwram => Some(&mut self.onchip_wram),
+            Region::Palette => Some(&mut self.palette),
+            Region::Vram => Some(&mut self.vram),
+            Region::Oam => Some(&mut self.oam),
+            Region::Sram => Some(&mut self.sram),
+            Region::Bios | Region::Io | Region::Rom | Region::Unmapped => None,
+        }
+    }
+
+    pub fn get_byte(&self, addr: u32) -> u8 {
+        let region = Region::from_address(addr);
+        let offset = Memory::mirrored_offset(region, addr);
+
+        if region == Region::Io {
+            return self.read_io_byte(addr);
+        }
+
+        match self.backing(region).get(offset) {
+            Some(val) => *val,
+            // Past the end of the cartridge the bus is left holding the halfword address
+            None if region == Region::Rom => ((addr >> 1) as u16 >> (8 * (addr & 0b1))) as u8,
+            None => 0,
+        }
+    }
+
+    // Byte writes follow the bus: 16-bit video memory takes the byte on both halves,
+    // OBJ VRAM and OAM ignore it
+    pub fn set_byte(&mut self, addr: u32, val: u8) {
+        trace!("set_byte {:8x} {:2x}", addr, val);
+
+        match Region::from_address(addr) {
+            Region::Io => {
+                let event = self.io.write_byte(addr, val);
+                self.io_event(event);
+            }
+            Region::Palette => self.write_halfword(addr & !0b1, val as u16 * 0x0101),
+            Region::Vram if Memory::mirrored_offset(Region::Vram, addr) < self.bg_vram_size() => {
+                self.write_halfword(addr & !0b1, val as u16 * 0x0101)
+            }
+            Region::Vram | Region::Oam => trace!("Ignoring byte write to {:8x}", addr),
+            _ => self.write_byte(addr, val),
+        }
+    }
+
+    pub fn set_halfword(&mut self, addr: u32, val: u16) {
+        trace!("set_halfword {:8x} {:4x}", addr, val);
+
+        match Region::from_address(addr) {
+            // Only the byte lane selected by the address reaches the 8-bit bus
+            Region::Sram => self.write_byte(addr, (val >> (8 * (addr & 0b1))) as u8),
+            Region::Io => {
+                let event = self.io.write_halfword(addr, val);
+                self.io_event(event);
+            }
+            _ => self.write_halfword(addr & !0b1, val),
+        }
+    }
+
+    pub fn set_word(&mut self, addr: u32, val: u32) {
+        trace!("set_word {:8x} {:8x}", addr, val);
+
+        match Region::from_address(addr) {
+            Region::Sram => self.write_byte(addr, (val >> (8 * (addr & 0b11))) as u8),
+            Region::Io => {
+                let addr = addr & !0b11;
+
+                let event = self.io.write_halfword(addr, val as u16);
+                self.io_event(event);
+                let event = self.io.write_halfword(addr + 2, (val >> 16) as u16);
+                self.io_event(event);
+            }
+            _ => {
+                let addr = addr & !0b11;
+                self.write_halfword(addr, val as u16);
+                self.write_halfword(addr + 2, (val >> 16) as u16);
+            }
+        }
+    }
+
+    // Passes register writes on to the peripheral that owns them
+    fn io_event(&mut self, event: Option<IoEvent>) {
+        match event {
+            Some(IoEvent::Interrupt) => self.update_irq(),
+            Some(IoEvent::Serial) => self.start_serial_transfer(),
+            Some(IoEvent::SoundControl) => self.sound_control(),
+            Some(IoEvent::SoundFifo {
+                channel,
+                data,
+                lanes,
+            }) => {
+                for (i, sample) in data.to_le_bytes().iter().enumerate() {
+                    if lanes & (0xFF << (8 * i)) != 0 {
+                        self.direct_sound.push(channel, *sample as i8);
+                    }
                 }
             }
-            _ => 0
+            Some(IoEvent::Dma(n)) => self.dma_control(n),
+            Some(IoEvent::Timer(n)) => self.timer_control(n),
+            Some(IoEvent::Waitcnt) if !self.prefetch_enabled() => self.prefetch.stop(),
+            Some(IoEvent::Waitcnt) if self.prefetch.active => {
+                self.prefetch.fetch_cycles = 1 + self.rom_wait_states(self.prefetch.head).1;
+            }
+            Some(IoEvent::Halt) => self.sleep(Sleep::Halt),
+            Some(IoEvent::Stop) => self.sleep(Sleep::Stop),
+            Some(IoEvent::Waitcnt) | None => {}
+        }
+    }
+
+    fn write_halfword(&mut self, addr: u32, val: u16) {
+        self.write_byte(addr, val as u8);
+        self.write_byte(addr + 1, (val >> 8) as u8);
+    }
+
+    // Stores a byte with no bus quirks applied
+    fn write_byte(&mut self, addr: u32, val: u8) {
+        let region = Region::from_address(addr);
+        let offset = Memory::mirrored_offset(region, addr);
+
+        match self.backing_mut(region) {
+            Some(backing) => backing[offset] = val,
+            None if region == Region::Rom => {
+                // No GPIO or save chip behind the cartridge bus yet
+                trace!("Ignoring ROM write to {:8x}", addr)
+            }
+            None => trace!("Ignoring write to {:8x}", addr),
+        }
+    }
+
+    // Background VRAM grows into the first OBJ tile block in the bitmap modes
+    fn bg_vram_size(&self) -> usize {
+        let video_mode = self.io.register(io::DISPCNT) & 0b111;
+
+        if video_mode >= 3 {
+            0x14000
+        } else {
+            0x10000
         }
     }
 
-    pub fn get_halfword(&self, _addr: u32) -> u16 {
-        unimplemented!("get_halfword");
+    // Reads are force aligned to their size, except on the 8-bit SRAM bus
+    pub fn get_halfword(&self, addr: u32) -> u16 {
+        if Region::from_address(addr) == Region::Sram {
+            // The 8-bit bus repeats the byte across the wider read
+            return self.get_byte(addr) as u16 * 0x0101;
+        }
+
+        let addr = addr & !0b1;
+        let result = ((self.get_byte(addr + 1) as u16) << 8) | self.get_byte(addr) as u16;
+        trace!("get_halfword {:8x} {:4x}", addr, result);
+
+        result
     }
 
     pub fn get_word(&self, addr: u32) -> u32 {
+        if Region::from_address(addr) == Region::Sram {
+            return self.get_byte(addr) as u32 * 0x01010101;
+        }
+
+        let addr = addr & !0b11;
         let result = ((self.get_byte(addr + 3) as u32) << 24)
             | ((self.get_byte(addr + 2) as u32) << 16)
             | ((self.get_byte(addr + 1) as u32) << 8)
-            | self.get_byte(addr + 0) as u32;
+            | self.get_byte(addr) as u32;
         trace!("get_word {:8x} {:8x}", addr, result);
 
-        return result;
+        result
+    }
+
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    // Clock cycles for an access of the given width, including the region's wait states
+    fn access_cycles(&self, addr: u32, width: u32, access: Access) -> u32 {
+        match (Region::from_address(addr), width) {
+            (Region::Ewram, 32) => 6,
+            (Region::Ewram, _) => 3,
+            (Region::Palette, 32) | (Region::Vram, 32) => 2,
+            (Region::Rom, _) => {
+                let (first, second) = self.rom_wait_states(addr);
+
+                // A burst can't continue into the next 128kb block
+                let sequential = access == Access::Sequential && addr & 0x1FFFF != 0;
+                let first_half = if sequential { 1 + second } else { 1 + first };
+
+                // The second half of a word is always sequential on the 16-bit bus
+                if width == 32 {
+                    first_half + 1 + second
+                } else {
+                    first_half
+                }
+            }
+            (Region::Sram, _) => 1 + FIRST_ACCESS_WAITS[(self.waitcnt() & 0b11) as usize],
+            _ => 1,
+        }
+    }
+
+    // First and second access wait states of the ROM mirror addr is in
+    fn rom_wait_states(&self, addr: u32) -> (u32, u32) {
+        let waitcnt = self.waitcnt() as u32;
+        let mirror = ((addr >> 25) - 4) as usize;
+        let field = waitcnt >> (2 + mirror * 3);
+
+        (
+            FIRST_ACCESS_WAITS[(field & 0b11) as usize],
+            SECOND_ACCESS_WAITS[mirror][((field >> 2) & 0b1) as usize],
+        )
+    }
+
+    pub fn set_prefetch_emulation(&mut self, enabled: bool) {
+        self.prefetch_emulation = enabled;
+        self.prefetch.stop();
+    }
+
+    fn prefetch_enabled(&self) -> bool {
+        self.prefetch_emulation && self.waitcnt() & WAITCNT_PREFETCH != 0
+    }
+
+    // Times an access and advances the clock, letting the prefetch buffer use any time the
+    // ROM bus is left free
+    fn timed_access(&mut self, addr: u32, width: u32, access: Access, kind: AccessKind) {
+        if Region::from_address(addr) != Region::Rom {
+            let cycles = self.access_cycles(addr, width, access);
+            self.tick(cycles);
+            return;
+        }
+
+        if kind == AccessKind::Data || !self.prefetch_enabled() {
+            self.prefetch.stop();
+            self.cycles += self.access_cycles(addr, width, access) as u64;
+            return;
+        }
+
+        for half in 0..(width / 16).max(1) {
+            let addr = (addr & !0b1) + half * 2;
+
+            match self.prefetch.take(addr) {
+                Some(cycles) => self.cycles += cycles as u64,
+                None => {
+                    let access = if half == 0 {
+                        access
+                    } else {
+                        Access::Sequential
+                    };
+
+                    let fetch_cycles = 1 + self.rom_wait_states(addr).1;
+
+                    self.cycles += self.access_cycles(addr, 16, access) as u64;
+                    self.prefetch.restart(addr + 2, fetch_cycles);
+                }
+            }
+        }
+    }
+
+    fn tick(&mut self, cycles: u32) {
+        self.cycles += cycles as u64;
+
+        if self.prefetch_enabled() {
+            self.prefetch.advance(cycles);
+        }
+    }
+
+    // A read through the bus, seeing open bus and the BIOS lock where the cpu would
+    fn read_bus(&mut self, addr: u32, width: u32, access: Access, kind: AccessKind) -> u32 {
+        self.timed_access(addr, width, access, kind);
+
+        let region = Region::from_address(addr);
+
+        if kind == AccessKind::Opcode {
+            let val = self.read_direct(addr, width);
+            self.fetched_opcode(region, addr, width, val);
+            return val;
+        }
+
+        self.watch(addr, width, false);
+
+        match region {
+            Region::Bios if !self.executing_bios => {
+                trace!("Protected bios read {:8x}", addr);
+                Memory::select_lanes(self.bios_opcode, addr, width)
+            }
+            Region::Unmapped => {
+                trace!("Open bus read {:8x}", addr);
+                Memory::select_lanes(self.open_bus, addr, width)
+            }
+            Region::Io if self.io.is_unused(addr) => {
+                trace!("Open bus read of unused io {:8x}", addr);
+                Memory::select_lanes(self.open_bus, addr, width)
+            }
+            _ => self.read_direct(addr, width),
+        }
+    }
+
+    fn read_direct(&self, addr: u32, width: u32) -> u32 {
+        match width {
+            8 => self.get_byte(addr) as u32,
+            16 => self.get_halfword(addr) as u32,
+            _ => self.get_word(addr),
+        }
+    }
+
+    // The part of a 32-bit bus value an access of width at addr sees
+    fn select_lanes(val: u32, addr: u32, width: u32) -> u32 {
+        match width {
+            8 => (val >> (8 * (addr & 0b11))) & 0xFF,
+            16 => (val >> (8 * (addr & 0b10))) & 0xFFFF,
+            _ => val,
+        }
+    }
+
+    // Updates the open bus value from an opcode fetch. Thumb fetches only drive half the bus,
+    // so what's left in the other half depends on the region's bus width
+    fn fetched_opcode(&mut self, region: Region, addr: u32, width: u32, val: u32) {
+        self.executing_bios = region == Region::Bios;
+
+        if self.executing_bios {
+            self.bios_opcode = self.get_word(addr & !0b11);
+        }
+
+        if width == 32 {
+            self.open_bus = val;
+            return;
+        }
+
+        let newest = val;
+        let previous = self.last_opcode_halfword as u32;
+        let aligned = addr & 0b10 == 0;
+
+        self.open_bus = match region {
+            Region::Bios | Region::Oam if aligned => {
+                newest | (self.get_halfword(addr + 2) as u32) << 16
+            }
+            Region::Bios | Region::Oam | Region::Iwram if !aligned => previous | newest << 16,
+            Region::Iwram => newest | previous << 16,
+            _ => newest * 0x00010001,
+        };
+
+        self.last_opcode_halfword = val as u16;
+    }
+
+    fn waitcnt(&self) -> u16 {
+        self.io.register(io::WAITCNT)
+    }
+
+    // Flags an interrupt in IF, for the peripheral that raised it
+    pub fn request_interrupt(&mut self, interrupt: Interrupt) {
+        let flags = self.io.register(io::IF);
+        self.io.set_register(io::IF, flags | interrupt.bit());
+
+        self.update_irq();
+    }
+
+    fn sleep(&mut self, sleep: Sleep) {
+        debug!("{:?} requested", sleep);
+
+        self.sleep = sleep;
+        self.update_irq();
+    }
+
+    fn update_irq(&mut self) {
+        let requested = self.io.register(io::IE) & self.io.register(io::IF);
+        let enabled = self.io.register(io::IME) & 0b1 != 0;
+        let pending = requested != 0;
+
+        // Waking doesn't need IME, only the interrupt to be enabled in IE
+        let wakes = match self.sleep {
+            Sleep::Awake => false,
+            Sleep::Halt => pending,
+            Sleep::Stop => requested & STOP_WAKE_INTERRUPTS != 0,
+        };
+        if wakes {
+            self.sleep = Sleep::Awake;
+        }
+
+        self.irq_since = match (enabled && pending, self.irq_since) {
+            (true, None) => Some(self.cycles),
+            (true, since) => since,
+            (false, _) => None,
+        };
+    }
+
+    fn run_event(&mut self, at: u64, event: Event) {
+        trace!("{:?} due at {}, now {}", event, at, self.cycles);
+
+        match event {
+            Event::HBlank => self.hblank(),
+            Event::HDraw => self.hdraw(at),
+            Event::SerialTransfer => self.finish_serial_transfer(),
+            Event::TimerOverflow(n) => self.timer_overflow(n, at),
+            Event::DmaStart(n) => self.run_dma(n),
+            Event::AudioSample => self.audio_sample(at),
+        }
+    }
+
+    fn hblank(&mut self) {
+        let dispstat = self.io.register(io::DISPSTAT);
+        self.io
+            .set_register(io::DISPSTAT, dispstat | DISPSTAT_HBLANK);
+
+        if dispstat & DISPSTAT_HBLANK_IRQ != 0 {
+            self.request_interrupt(Interrupt::HBlank);
+        }
+
+        // HBlank DMA only happens on visible lines
+        if self.io.register(io::VCOUNT) < VISIBLE_LINES {
+            self.trigger_dma(Timing::HBlank);
+        }
+    }
+
+    // Starts the scanline after the one that just ended at the given cycle
+    fn hdraw(&mut self, at: u64) {
+        self.scheduler.schedule(at + HDRAW_CYCLES, Event::HBlank);
+        self.scheduler.schedule(at + SCANLINE_CYCLES, Event::HDraw);
+
+        let line = (self.io.register(io::VCOUNT) + 1) % TOTAL_LINES;
+        self.io.set_register(io::VCOUNT, line);
+
+        let mut dispstat = self.io.register(io::DISPSTAT) & !DISPSTAT_HBLANK;
+
+        // The VBlank flag is already clear on the last line
+        if line == VISIBLE_LINES {
+            dispstat |= DISPSTAT_VBLANK;
+        } else if line == TOTAL_LINES - 1 {
+            dispstat &= !DISPSTAT_VBLANK;
+        }
+
+        let vcount_match = line == dispstat >> 8;
+        if vcount_match {
+            dispstat |= DISPSTAT_VCOUNT_MATCH;
+        } else {
+            dispstat &= !DISPSTAT_VCOUNT_MATCH;
+        }
+
+        self.io.set_register(io::DISPSTAT, dispstat);
+
+        if line == VISIBLE_LINES {
+            self.trigger_dma(Timing::VBlank);
+
+            if dispstat & DISPSTAT_VBLANK_IRQ != 0 {
+                self.request_interrupt(Interrupt::VBlank);
+            }
+        }
+        if vcount_match && dispstat & DISPSTAT_VCOUNT_IRQ != 0 {
+            self.request_interrupt(Interrupt::VCount);
+        }
+    }
+
+    // Only transfers clocked by the GBA can finish, as there's never a link partner
+    fn start_serial_transfer(&mut self) {
+        let control = self.io.register(io::SIOCNT);
+
+        if control & SIOCNT_INTERNAL_CLOCK == 0 {
+            debug!("Serial transfer waiting on an external clock");
+            return;
+        }
+
+        let bits = if control & SIOCNT_32BIT != 0 { 32 } else { 8 };
+        let cycles_per_bit = if control & SIOCNT_2MHZ != 0 { 8 } else { 64 };
+
+        self.scheduler.cancel(Event::SerialTransfer);
+        self.scheduler
+            .schedule(self.cycles + bits * cycles_per_bit, Event::SerialTransfer);
+    }
+
+    fn finish_serial_transfer(&mut self) {
+        let control = self.io.register(io::SIOCNT);
+
+        // With nothing connected the line stays high, so only 1s are shifted in
+        if control & SIOCNT_32BIT != 0 {
+            self.io.set_register(io::SIODATA32, 0xFFFF);
+            self.io.set_register(io::SIODATA32 + 2, 0xFFFF);
+        } else {
+            let data = self.io.register(io::SIODATA8);
+            self.io.set_register(io::SIODATA8, data | 0x00FF);
+        }
+
+        self.io.set_register(io::SIOCNT, control & !SIOCNT_START);
+
+        if control & SIOCNT_IRQ != 0 {
+            self.request_interrupt(Interrupt::Serial);
+        }
+    }
+
+    // IO reads, with the timer counters worked out from the clock
+    fn read_io_byte(&self, addr: u32) -> u8 {
+        let offset = addr & 0x3FE;
+
+        match offset {
+            0x100 | 0x104 | 0x108 | 0x10C => {
+                let n = ((offset - io::TM0CNT_L) / 4) as usize;
+                let counter = self.timers[n].counter(self.cycles, self.io.register(offset));
+
+                (counter >> (8 * (addr & 0b1))) as u8
+            }
+            _ => self.io.read_byte(addr).unwrap_or(0),
+        }
+    }
+
+    fn timer_control(&mut self, n: usize) {
+        let control = self.io.register(io::TM0CNT_H + 4 * n as u32);
+        let reload = self.io.register(io::TM0CNT_L + 4 * n as u32);
+
+        self.scheduler.cancel(Event::TimerOverflow(n));
+
+        if let Some(at) = self.timers[n].set_control(control, reload, self.cycles) {
+            self.scheduler.schedule(at, Event::TimerOverflow(n));
+        }
+    }
+
+    fn timer_overflow(&mut self, n: usize, at: u64) {
+        let reload = self.io.register(io::TM0CNT_L + 4 * n as u32);
+
+        if let Some(next) = self.timers[n].overflow(reload, at) {
+            self.scheduler.schedule(next, Event::TimerOverflow(n));
+        }
+
+        self.timer_overflowed(n);
+    }
+
+    // Everything clocked by a timer overflowing: its IRQ, Direct Sound and the next timer if
+    // it's counting up
+    fn timer_overflowed(&mut self, n: usize) {
+        if self.timers[n].irq_enabled() {
+            self.request_interrupt(Interrupt::timer(n));
+        }
+
+        if n < 2 {
+            self.feed_direct_sound(n);
+        }
+
+        if n < 3 && self.timers[n + 1].enabled() && self.timers[n + 1].cascade() {
+            let reload = self.io.register(io::TM0CNT_L + 4 * (n as u32 + 1));
+
+            if self.timers[n + 1].count_up(reload) {
+                self.timer_overflowed(n + 1);
+            }
+        }
+    }
+
+    // Each Direct Sound channel plays its next sample when the timer it's clocked by overflows
+    fn feed_direct_sound(&mut self, timer: usize) {
+        if self.io.register(io::SOUNDCNT_X) & SOUNDCNT_MASTER_ENABLE == 0 {
+            return;
+        }
+
+        let control = self.io.register(io::SOUNDCNT_H);
+        let timer_selects = [SOUNDCNT_FIFO_A_TIMER1, SOUNDCNT_FIFO_B_TIMER1];
+
+        for (channel, select) in timer_selects.iter().enumerate() {
+            let channel_timer = if control & select != 0 { 1 } else { 0 };
+
+            if channel_timer == timer && self.direct_sound.timer_overflow(channel) {
+                self.request_fifo_refill(channel);
+            }
+        }
+    }
+
+    // Refills come from whichever of DMA 1 and 2 is set up to write to the channel's FIFO
+    fn request_fifo_refill(&mut self, channel: usize) {
+        let fifo = 0x04000000 | [io::FIFO_A, io::FIFO_B][channel];
+
+        for n in 1..=2 {
+            let dma = &self.dma[n];
+
+            if dma.enabled() && dma.timing() == Timing::Special && dma.dst() == fifo {
+                self.scheduler.schedule(self.cycles, Event::DmaStart(n));
+            }
+        }
+    }
+
+    fn sound_control(&mut self) {
+        let enabled = self.io.register(io::SOUNDCNT_X) & SOUNDCNT_MASTER_ENABLE != 0;
+
+        if enabled && !self.sampling {
+            self.scheduler
+                .schedule(self.cycles + SAMPLE_CYCLES, Event::AudioSample);
+        } else if !enabled && self.sampling {
+            self.scheduler.cancel(Event::AudioSample);
+        }
+        self.sampling = enabled;
+
+        let control = self.io.register(io::SOUNDCNT_H);
+
+        if control & SOUNDCNT_FIFO_A_RESET != 0 {
+            self.direct_sound.reset(0);
+        }
+        if control & SOUNDCNT_FIFO_B_RESET != 0 {
+            self.direct_sound.reset(1);
+        }
+
+        // The resets act once, and always read back as zero
+        let resets = SOUNDCNT_FIFO_A_RESET | SOUNDCNT_FIFO_B_RESET;
+        self.io.set_register(io::SOUNDCNT_H, control & !resets);
+    }
+
+    fn audio_sample(&mut self, at: u64) {
+        self.scheduler
+            .schedule(at + SAMPLE_CYCLES, Event::AudioSample);
+
+        self.direct_sound.sample(self.io.register(io::SOUNDCNT_H));
+    }
+
+    // The samples Direct Sound channels A and B are currently playing
+    pub fn direct_sound_output(&self) -> [i8; 2] {
+        self.direct_sound.output()
+    }
+
+    // Left and right sound output at 32768Hz, since the last time it was taken
+    pub fn take_audio_samples(&mut self) -> Vec<[i16; 2]> {
+        self.direct_sound.take_samples()
+    }
+
+    // The halfword registers of a DMA channel, from DMAxSAD
+    fn dma_register(&self, n: usize, index: u32) -> u16 {
+        self.io.register(io::DMA0SAD + 12 * n as u32 + 2 * index)
+    }
+
+    fn dma_control(&mut self, n: usize) {
+        let src = self.dma_register(n, 0) as u32 | (self.dma_register(n, 1) as u32) << 16;
+        let dst = self.dma_register(n, 2) as u32 | (self.dma_register(n, 3) as u32) << 16;
+        let count = self.dma_register(n, 4);
+        let control = self.dma_register(n, 5);
+
+        let dma = &mut self.dma[n];
+        let started = dma.set_control(n, control, src, dst, count);
+
+        if !dma.enabled() {
+            self.scheduler.cancel(Event::DmaStart(n));
+        } else if started && dma.timing() == Timing::Immediate {
+            self.scheduler
+                .schedule(self.cycles + DMA_START_DELAY, Event::DmaStart(n));
+        }
+    }
+
+    fn trigger_dma(&mut self, timing: Timing) {
+        for n in 0..4 {
+            if self.dma[n].enabled() && self.dma[n].timing() == timing {
+                self.scheduler.schedule(self.cycles, Event::DmaStart(n));
+            }
+        }
+    }
+
+    // Runs a whole transfer, with the cpu waiting on the bus until it's done
+    fn run_dma(&mut self, n: usize) {
+        if !self.dma[n].enabled() {
+            return;
+        }
+
+        let fifo = self.dma[n].timing() == Timing::Special;
+        if fifo && (n == 0 || n == 3) {
+            debug!("DMA {} special timing isn't emulated", n);
+            return;
+        }
+
+        let (units, word) = self.dma[n].transfer(fifo);
+        let mut access = Access::NonSequential;
+
+        for (src, dst) in units {
+            if word {
+                let val = self.read_32(src, access, AccessKind::Data);
+                self.write_32(dst, val, access);
+            } else {
+                let val = self.read_16(src, access, AccessKind::Data);
+                self.write_16(dst, val, access);
+            }
+            access = Access::Sequential;
+        }
+
+        // Getting on and off the bus
+        self.idle(2);
+
+        let dst = self.dma_register(n, 2) as u32 | (self.dma_register(n, 3) as u32) << 16;
+        if !self.dma[n].finish(n, dst, self.dma_register(n, 4)) {
+            let cnt_h = io::DMA0CNT_H + 12 * n as u32;
+            self.io
+                .set_register(cnt_h, self.io.register(cnt_h) & !0x8000);
+        }
+
+        if self.dma[n].irq_enabled() {
+            self.request_interrupt(Interrupt::dma(n));
+        }
+    }
+
+    // Calls watcher with every data access the cpu makes through the bus
+    pub fn set_watcher(&mut self, watcher: Option<Box<dyn FnMut(DataAccess)>>) {
+        self.watcher = watcher;
+    }
+
+    fn watch(&mut self, addr: u32, width: u32, write: bool) {
+        let locked = self.locked;
+
+        if let Some(watcher) = self.watcher.as_mut() {
+            watcher(DataAccess {
+                addr,
+                width,
+                write,
+                locked,
+            });
+        }
+    }
+
+    // True while a locked read-modify-write is in progress
+    pub fn is_locked(&self) -> bool {
+        self.locked
+    }
+}
+
+impl Bus for Memory {
+    fn read_8(&mut self, addr: u32, access: Access, kind: AccessKind) -> u8 {
+        self.read_bus(addr, 8, access, kind) as u8
+    }
+
+    fn read_16(&mut self, addr: u32, access: Access, kind: AccessKind) -> u16 {
+        self.read_bus(addr, 16, access, kind) as u16
+    }
+
+    fn read_32(&mut self, addr: u32, access: Access, kind: AccessKind) -> u32 {
+        self.read_bus(addr, 32, access, kind)
+    }
+
+    fn write_8(&mut self, addr: u32, val: u8, access: Access) {
+        self.timed_access(addr, 8, access, AccessKind::Data);
+        self.watch(addr, 8, true);
+        self.set_byte(addr, val)
+    }
+
+    fn write_16(&mut self, addr: u32, val: u16, access: Access) {
+        self.timed_access(addr, 16, access, AccessKind::Data);
+        self.watch(addr, 16, true);
+        self.set_halfword(addr, val)
+    }
+
+    fn write_32(&mut self, addr: u32, val: u32, access: Access) {
+        self.timed_access(addr, 32, access, AccessKind::Data);
+        self.watch(addr, 32, true);
+        self.set_word(addr, val)
+    }
+
+    fn idle(&mut self, cycles: u32) {
+        self.tick(cycles);
+    }
+
+    fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    // Both halves of a swap are ordinary bus accesses, made while the bus is locked
+    fn swap_32(&mut self, addr: u32, val: u32) -> u32 {
+        self.locked = true;
+
+        let old = self
+            .read_bus(addr, 32, Access::NonSequential, AccessKind::Data)
+            .rotate_right(8 * (addr & 0b11));
+        self.write_32(addr, val, Access::NonSequential);
+
+        self.locked = false;
+
+        trace!("swap_word {:8x} {:8x} -> {:8x}", addr, old, val);
+
+        old
+    }
+
+    fn swap_8(&mut self, addr: u32, val: u8) -> u8 {
+        self.locked = true;
+
+        let old = self.read_bus(addr, 8, Access::NonSequential, AccessKind::Data) as u8;
+        self.write_8(addr, val, Access::NonSequential);
+
+        self.locked = false;
+
+        trace!("swap_byte {:8x} {:2x} -> {:2x}", addr, old, val);
+
+        old
+    }
+}
+
+impl System for Memory {
+    fn irq_line(&mut self) -> bool {
+        match self.irq_since {
+            Some(since) => self.cycles >= since + IRQ_DELAY,
+            None => false,
+        }
+    }
+
+    fn next_event(&self) -> u64 {
+        self.scheduler.next_at().unwrap_or(u64::MAX)
+    }
+
+    fn run_events(&mut self) {
+        while let Some((at, event)) = self.scheduler.pop_due(self.cycles) {
+            self.run_event(at, event);
+        }
+    }
+
+    fn halted(&self) -> bool {
+        self.sleep != Sleep::Awake
+    }
+}
+
+impl Default for Memory {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[test]
+    fn test_region_decode() {
+        assert_eq!(Region::Bios, Region::from_address(0x00003FFF));
+        assert_eq!(Region::Unmapped, Region::from_address(0x00004000));
+        assert_eq!(Region::Ewram, Region::from_address(0x02FFFFFF));
+        assert_eq!(Region::Io, Region::from_address(0x040003FE));
+        assert_eq!(Region::Unmapped, Region::from_address(0x04000400));
+        assert_eq!(Region::Rom, Region::from_address(0x0D000000));
+        assert_eq!(Region::Sram, Region::from_address(0x0E00FFFF));
+        assert_eq!(Region::Unmapped, Region::from_address(0x10000000));
+
+        assert_eq!(16, Region::Vram.bus_width());
+        assert_eq!(8, Region::Sram.bus_width());
+    }
+
+    #[test]
+    fn test_wram_mirrors() {
+        let mut mem = Memory::new();
+
+        mem.set_word(0x03000000, 0x12345678);
+        assert_eq!(0x12345678, mem.get_word(0x03008000));
+        assert_eq!(0x12345678, mem.get_word(0x03FF8000));
+
+        mem.set_word(0x02040010, 0x11223344);
+        assert_eq!(0x11223344, mem.get_word(0x02000010));
+    }
+
+    #[test]
+    fn test_vram_mirrors() {
+        let mut mem = Memory::new();
+
+        mem.set_halfword(0x06010000, 0x1234);
+        mem.set_halfword(0x06000000, 0x5678);
+
+        // The 32kb above 96kb repeats 64-96kb, then the whole 128kb repeats
+        assert_eq!(0x1234, mem.get_halfword(0x06018000));
+        assert_eq!(0x5678, mem.get_halfword(0x06020000));
+        assert_eq!(0x1234, mem.get_halfword(0x06038000));
+    }
+
+    #[test]
+    fn test_small_regions() {
+        let mut mem = Memory::new();
+
+        mem.set_halfword(0x05000002, 0x7fff);
+        mem.set_word(0x07000000, 0xaabbccdd);
+        mem.set_halfword(0x04000000, 0x0403);
+
+        assert_eq!(0x7fff, mem.get_halfword(0x05000402));
+        assert_eq!(0xaabbccdd, mem.get_word(0x07000400));
+        assert_eq!(0x0403, mem.get_halfword(0x04000000));
+    }
+
+    #[test]
+    fn test_rom_wait_state_mirrors() {
+        let mem = Memory::new_with_bios_and_rom(vec![], vec![0x11, 0x22, 0x33, 0x44]);
+
+        assert_eq!(0x44332211, mem.get_word(0x08000000));
+        assert_eq!(0x44332211, mem.get_word(0x0A000000));
+        assert_eq!(0x44332211, mem.get_word(0x0C000000));
+
+        // Past the end of the image each halfword reads as its address / 2
+        assert_eq!(0x00030002, mem.get_word(0x08000004));
+    }
+
+    #[test]
+    fn test_sram_byte_bus() {
+        let mut mem = Memory::new();
+
+        mem.set_byte(0x0E000001, 0x5a);
+
+        assert_eq!(0x5a, mem.get_byte(0x0E010001));
+        assert_eq!(0x5a5a, mem.get_halfword(0x0E000001));
+        assert_eq!(0x5a5a5a5a, mem.get_word(0x0E000001));
+    }
+
+    #[test]
+    fn test_byte_writes_to_video_memory() {
+        let mut mem = Memory::new();
+
+        mem.set_byte(0x05000001, 0x1f);
+        assert_eq!(0x1f1f, mem.get_halfword(0x05000000));
+
+        mem.set_byte(0x06000004, 0xab);
+        assert_eq!(0xabab, mem.get_halfword(0x06000004));
+
+        // OBJ tiles and OAM ignore byte writes
+        mem.set_byte(0x06010000, 0xab);
+        mem.set_byte(0x07000000, 0xab);
+        assert_eq!(0, mem.get_halfword(0x06010000));
+        assert_eq!(0, mem.get_halfword(0x07000000));
+
+        // Unless a bitmap mode moves the boundary
+        mem.set_halfword(0x04000000, 0x0003);
+        mem.set_byte(0x06010000, 0xab);
+        assert_eq!(0xabab, mem.get_halfword(0x06010000));
+    }
+
+    #[test]
+    fn test_misaligned_accesses_force_align() {
+        let mut mem = Memory::new();
+
+        mem.set_word(0x03000003, 0x11223344);
+        assert_eq!(0x11223344, mem.get_word(0x03000000));
+        assert_eq!(0x11223344, mem.get_word(0x03000002));
+
+        mem.set_halfword(0x03000005, 0x5566);
+        assert_eq!(0x5566, mem.get_halfword(0x03000004));
+        assert_eq!(0x5566, mem.get_halfword(0x03000005));
+    }
+
+    #[test]
+    fn test_rom_and_sram_writes() {
+        let mut mem = Memory::new_with_bios_and_rom(vec![], vec![0x11, 0x22]);
+
+        mem.set_halfword(0x08000000, 0xffff);
+        assert_eq!(0x2211, mem.get_halfword(0x08000000));
+
+        // Wide writes to SRAM store the byte lane for the address
+        mem.set_word(0x0E000002, 0x11223344);
+        assert_eq!(0x22, mem.get_byte(0x0E000002));
+    }
+
+    #[test]
+    fn test_access_timing() {
+        let mut mem = Memory::new();
+        let n = Access::NonSequential;
+
+        mem.read_32(0x03000000, n, AccessKind::Data);
+        assert_eq!(1, mem.cycles());
+
+        mem.read_32(0x02000000, n, AccessKind::Data);
+        assert_eq!(1 + 6, mem.cycles());
+
+        mem.write_16(0x06000000, 0, n);
+        mem.idle(2);
+        assert_eq!(1 + 6 + 1 + 2, mem.cycles());
+    }
+
+    #[test]
+    fn test_rom_wait_states() {
+        let mem = Memory::new();
+        let (n, s) = (Access::NonSequential, Access::Sequential);
+
+        // Reset WAITCNT waits 4 on first accesses and the slower second access setting
+        assert_eq!(5, mem.access_cycles(0x08000000, 16, n));
+        assert_eq!(3, mem.access_cycles(0x08000002, 16, s));
+        assert_eq!(5, mem.access_cycles(0x0A000002, 16, s));
+        assert_eq!(9, mem.access_cycles(0x0C000002, 16, s));
+        // SRAM takes any width in one byte access
+        assert_eq!(5, mem.access_cycles(0x0E000000, 32, n));
+
+        let mut mem = mem;
+        // WS0 3,1  WS1 2,1  WS2 8,1  SRAM 2
+        mem.set_halfword(0x04000204, 0b0000_0111_1101_0110);
+
+        assert_eq!(4 + 2, mem.access_cycles(0x08000000, 32, n));
+        assert_eq!(3, mem.access_cycles(0x0A000000, 16, n));
+        assert_eq!(2, mem.access_cycles(0x0C000002, 16, s));
+        assert_eq!(9, mem.access_cycles(0x0C000000, 16, n));
+        assert_eq!(3, mem.access_cycles(0x0E000000, 8, n));
+
+        // Sequential access into a new 128kb block pays the first access wait
+        assert_eq!(4, mem.access_cycles(0x08020000, 16, s));
+    }
+
+    fn run_prefetch_loop(mem: &mut Memory) -> u64 {
+        let (n, s) = (Access::NonSequential, Access::Sequential);
+
+        mem.read_16(0x08000000, n, AccessKind::Opcode);
+        mem.idle(6);
+        mem.read_16(0x08000002, s, AccessKind::Opcode);
+        mem.read_16(0x08000004, s, AccessKind::Opcode);
+        mem.read_16(0x08000006, s, AccessKind::Opcode);
+
+        mem.cycles()
+    }
+
+    #[test]
+    fn test_prefetch_buffer() {
+        let mut mem = Memory::new();
+        mem.set_halfword(0x04000204, WAITCNT_PREFETCH);
+
+        // Two halfwords are read ahead during the idle cycles and the third is nearly there
+        assert_eq!(5 + 6 + 1 + 1 + 1, run_prefetch_loop(&mut mem));
+
+        let mut mem = Memory::new();
+        mem.set_halfword(0x04000204, WAITCNT_PREFETCH);
+        mem.set_prefetch_emulation(false);
+
+        assert_eq!(5 + 6 + 3 * 3, run_prefetch_loop(&mut mem));
+    }
+
+    #[test]
+    fn test_prefetch_stops_at_end_of_rom() {
+        let mut mem = Memory::new();
+        mem.set_halfword(0x04000204, WAITCNT_PREFETCH);
+
+        // Idling before the buffer has started doesn't read ahead from anywhere
+        mem.idle(10);
+
+        mem.read_16(0x0DFFFFFC, Access::NonSequential, AccessKind::Opcode);
+        mem.idle(100);
+        assert_eq!(1, mem.prefetch.count);
+
+        let start = mem.cycles();
+        mem.read_16(0x0DFFFFFE, Access::Sequential, AccessKind::Opcode);
+        assert_eq!(1, mem.cycles() - start);
+        mem.idle(100);
+    }
+
+    #[test]
+    fn test_waitcnt_write_after_last_rom_fetch() {
+        let mut mem = Memory::new();
+        mem.set_halfword(0x04000204, WAITCNT_PREFETCH);
+
+        // Taking the last halfword from the buffer leaves nothing after it to read ahead from
+        mem.read_16(0x0DFFFFFC, Access::NonSequential, AccessKind::Opcode);
+        mem.read_16(0x0DFFFFFE, Access::Sequential, AccessKind::Opcode);
+        assert!(!mem.prefetch.active);
+
+        mem.write_16(0x04000204, WAITCNT_PREFETCH | 0x0014, Access::NonSequential);
+        mem.idle(10);
+        assert_eq!(0, mem.prefetch.count);
+    }
+
+    #[test]
+    fn test_prefetch_stopped_by_data_access() {
+        let mut mem = Memory::new();
+        mem.set_halfword(0x04000204, WAITCNT_PREFETCH);
+
+        mem.read_16(0x08000000, Access::NonSequential, AccessKind::Opcode);
+        mem.idle(6);
+        mem.read_16(0x08001000, Access::NonSequential, AccessKind::Data);
+
+        let start = mem.cycles();
+        mem.read_16(0x08000002, Access::NonSequential, AccessKind::Opcode);
+
+        assert_eq!(5, mem.cycles() - start);
+    }
+
+    #[test]
+    fn test_open_bus_after_arm_fetch() {
+        let mut mem = Memory::new();
+        let n = Access::NonSequential;
+
+        mem.set_word(0x03000000, 0xe3a01005);
+        mem.read_32(0x03000000, n, AccessKind::Opcode);
+
+        assert_eq!(0xe3a01005, mem.read_32(0x00004000, n, AccessKind::Data));
+        assert_eq!(0xe3a0, mem.read_16(0x10000002, n, AccessKind::Data));
+        assert_eq!(0x10, mem.read_8(0x01000001, n, AccessKind::Data));
+    }
+
+    #[test]
+    fn test_open_bus_after_thumb_fetch() {
+        let mut mem = Memory::new_with_bios_and_rom(vec![], vec![]);
+        let n = Access::NonSequential;
+
+        mem.set_word(0x03000000, 0x22221111);
+
+        // From the 16-bit ROM bus both halves see the newest fetch
+        mem.read_16(0x08000000, n, AccessKind::Opcode);
+        mem.read_16(0x08000002, n, AccessKind::Opcode);
+        assert_eq!(0x00010001, mem.read_32(0x10000000, n, AccessKind::Data));
+
+        // IWRAM keeps the previous fetch in the other half
+        mem.read_16(0x03000000, n, AccessKind::Opcode);
+        assert_eq!(0x00011111, mem.read_32(0x10000000, n, AccessKind::Data));
+        mem.read_16(0x03000002, n, AccessKind::Opcode);
+        assert_eq!(0x22221111, mem.read_32(0x10000000, n, AccessKind::Data));
+    }
+
+    #[test]
+    fn test_bios_read_protection() {
+        let mut bios = vec![0; 0x4000];
+        bios[0x10..0x18].copy_from_slice(&[0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88]);
+
+        let mut mem = Memory::new_with_bios_and_rom(bios, vec![]);
+        let n = Access::NonSequential;
+
+        // Code running in the BIOS can read it
+        mem.read_32(0x00000010, n, AccessKind::Opcode);
+        assert_eq!(0x88776655, mem.read_32(0x00000014, n, AccessKind::Data));
+
+        // From anywhere else reads see the last BIOS fetch
+        mem.read_32(0x08000000, n, AccessKind::Opcode);
+        assert_eq!(0x44332211, mem.read_32(0x00000014, n, AccessKind::Data));
+        assert_eq!(0x33, mem.read_8(0x00000102, n, AccessKind::Data));
+
+        // Emulated BIOS reads as it would after booting
+        let mut mem = Memory::new_with_rom(vec![]);
+        assert_eq!(
+            BIOS_OPCODE_AFTER_BOOT,
+            mem.read_32(0x00000000, n, AccessKind::Data)
+        );
+    }
+
+    #[test]
+    fn test_io_through_memory() {
+        let mut mem = Memory::new();
+        let n = Access::NonSequential;
+
+        // VCOUNT is read-only, and a word write reaches both halves
+        mem.set_word(0x04000004, 0xffffffff);
+        assert_eq!(0x0000ff38, mem.get_word(0x04000004));
+
+        assert_eq!(0x03ff, mem.get_halfword(0x04000130));
+
+        // Unused registers read as open bus
+        mem.set_word(0x03000000, 0xe3a01005);
+        mem.read_32(0x03000000, n, AccessKind::Opcode);
+        assert_eq!(0xe3a01005, mem.read_32(0x040000E0, n, AccessKind::Data));
+    }
+
+    #[test]
+    fn test_halt_and_stop() {
+        let mut mem = Memory::new();
+        mem.set_halfword(
+            0x04000200,
+            Interrupt::VBlank.bit() | Interrupt::Keypad.bit(),
+        );
+
+        // Halt ends on any enabled interrupt, even with IME clear
+        mem.set_byte(0x04000301, 0x00);
+        assert!(mem.halted());
+
+        mem.request_interrupt(Interrupt::HBlank);
+        assert!(mem.halted());
+        mem.request_interrupt(Interrupt::VBlank);
+        assert!(!mem.halted());
+
+        // Stop only ends on interrupts from outside, and halting with one pending doesn't sleep
+        mem.set_halfword(0x04000202, 0xFFFF);
+        mem.set_byte(0x04000301, 0x80);
+        mem.request_interrupt(Interrupt::VBlank);
+        assert!(mem.halted());
+        mem.request_interrupt(Interrupt::Keypad);
+        assert!(!mem.halted());
+
+        mem.set_byte(0x04000301, 0x00);
+        assert!(!mem.halted());
+    }
+
+    #[test]
+    fn test_irq_line() {
+        let mut mem = Memory::new();
+
+        // Nothing reaches the cpu until the interrupt is enabled in IE and IME
+        mem.request_interrupt(Interrupt::VBlank);
+        mem.idle(10);
+        assert!(!mem.irq_line());
+
+        mem.set_halfword(0x04000200, Interrupt::VBlank.bit());
+        mem.set_halfword(0x04000208, 1);
+        assert!(!mem.irq_line());
+
+        mem.idle(IRQ_DELAY as u32);
+        assert!(mem.irq_line());
+
+        // Acknowledging drops the line straight away
+        mem.set_halfword(0x04000202, Interrupt::VBlank.bit());
+        assert!(!mem.irq_line());
+    }
+
+    #[test]
+    fn test_scanline_timing() {
+        let mut mem = Memory::new();
+        mem.set_halfword(
+            0x04000004,
+            (160 << 8) | DISPSTAT_VCOUNT_IRQ | DISPSTAT_HBLANK_IRQ,
+        );
+
+        mem.idle(HDRAW_CYCLES as u32);
+        mem.run_events();
+        assert_eq!(DISPSTAT_HBLANK, mem.get_halfword(0x04000004) & 0b111);
+        assert_eq!(Interrupt::HBlank.bit(), mem.get_halfword(0x04000202));
+
+        // VBlank starts on line 160 and its flag clears again on the last line
+        mem.idle(160 * SCANLINE_CYCLES as u32 - HDRAW_CYCLES as u32);
+        mem.run_events();
+        assert_eq!(160, mem.get_halfword(0x04000006));
+        assert_eq!(
+            DISPSTAT_VBLANK | DISPSTAT_VCOUNT_MATCH,
+            mem.get_halfword(0x04000004) & 0b111
+        );
+        assert_ne!(0, mem.get_halfword(0x04000202) & Interrupt::VCount.bit());
+        assert_eq!(0, mem.get_halfword(0x04000202) & Interrupt::VBlank.bit());
+
+        mem.idle(67 * SCANLINE_CYCLES as u32);
+        mem.run_events();
+        assert_eq!(227, mem.get_halfword(0x04000006));
+        assert_eq!(0, mem.get_halfword(0x04000004) & DISPSTAT_VBLANK);
+
+        assert_eq!(227 * SCANLINE_CYCLES + HDRAW_CYCLES, mem.next_event());
+    }
+
+    #[test]
+    fn test_serial_transfer_without_partner() {
+        let mut mem = Memory::new();
+        mem.set_halfword(0x04000200, Interrupt::Serial.bit());
+        mem.set_byte(0x0400012A, 0x12);
+
+        // 8 bits at 256KHz
+        mem.set_halfword(
+            0x04000128,
+            SIOCNT_IRQ | SIOCNT_START | SIOCNT_INTERNAL_CLOCK,
+        );
+        let start = mem.cycles();
+
+        mem.idle(8 * 64 - 1);
+        mem.run_events();
+        assert_ne!(0, mem.get_halfword(0x04000128) & SIOCNT_START);
+
+        mem.idle((start + 8 * 64 - mem.cycles()) as u32);
+        mem.run_events();
+        assert_eq!(0, mem.get_halfword(0x04000128) & SIOCNT_START);
+        assert_eq!(0xFF, mem.get_byte(0x0400012A));
+        assert_eq!(Interrupt::Serial.bit(), mem.get_halfword(0x04000202));
+    }
+
+    #[test]
+    fn test_timer_counter_and_overflow() {
+        let mut mem = Memory::new();
+        mem.set_halfword(0x04000200, Interrupt::Timer0.bit());
+
+        // Reload 0xFFF0 at 64 cycles per tick, with its IRQ
+        mem.set_halfword(0x04000100, 0xFFF0);
+        mem.set_halfword(0x04000102, 0x00C1);
+        let start = mem.cycles();
+        assert_eq!(0xFFF0, mem.get_halfword(0x04000100));
+
+        mem.idle(64 * 3);
+        assert_eq!(0xFFF3, mem.get_halfword(0x04000100));
+
+        mem.idle(64 * 0x10 - 64 * 3 - 1);
+        mem.run_events();
+        assert_eq!(0xFFFF, mem.get_halfword(0x04000100));
+        assert_eq!(0, mem.get_halfword(0x04000202));
+
+        mem.idle(1);
+        mem.run_events();
+        assert_eq!(start + 64 * 0x10, mem.cycles());
+        assert_eq!(0xFFF0, mem.get_halfword(0x04000100));
+        assert_eq!(Interrupt::Timer0.bit(), mem.get_halfword(0x04000202));
+
+        // Stopping freezes the counter
+        mem.idle(64 * 5);
+        mem.set_halfword(0x04000102, 0x0001);
+        mem.idle(64 * 5);
+        assert_eq!(0xFFF5, mem.get_halfword(0x04000100));
+    }
+
+    #[test]
+    fn test_timer_cascade() {
+        let mut mem = Memory::new();
+        mem.set_halfword(0x04000200, Interrupt::Timer2.bit());
+
+        // Timer 0 overflows every 2 cycles, timer 1 every 3 of those, timer 2 every 2 of those
+        mem.set_halfword(0x04000100, 0xFFFE);
+        mem.set_halfword(0x04000104, 0xFFFD);
+        mem.set_halfword(0x04000108, 0xFFFE);
+        mem.set_halfword(0x0400010A, 0x00C4);
+        mem.set_halfword(0x04000106, 0x0084);
+        mem.set_halfword(0x04000102, 0x0080);
+
+        for _ in 0..11 {
+            mem.idle(1);
+            mem.run_events();
+        }
+        assert_eq!(0xFFFF, mem.get_halfword(0x04000104));
+        assert_eq!(0xFFFF, mem.get_halfword(0x04000108));
+        assert_eq!(0, mem.get_halfword(0x04000202));
+
+        mem.idle(1);
+        mem.run_events();
+        assert_eq!(0xFFFD, mem.get_halfword(0x04000104));
+        assert_eq!(0xFFFE, mem.get_halfword(0x04000108));
+        assert_eq!(Interrupt::Timer2.bit(), mem.get_halfword(0x04000202));
+    }
+
+    #[test]
+    fn test_timer_feeds_direct_sound() {
+        let mut mem = Memory::new();
+        mem.set_halfword(0x04000084, SOUNDCNT_MASTER_ENABLE);
+
+        // FIFO A on timer 0, FIFO B on timer 1
+        mem.set_halfword(0x04000082, SOUNDCNT_FIFO_B_TIMER1);
+        mem.set_word(0x040000A0, 0x04030201);
+        mem.set_byte(0x040000A4, 0xFF);
+
+        mem.set_halfword(0x04000100, 0xFFFF);
+        mem.set_halfword(0x04000102, 0x0080);
+
+        mem.idle(2);
+        mem.run_events();
+        assert_eq!([2, 0], mem.direct_sound_output());
+
+        // Resetting empties the FIFO without changing what's playing
+        mem.set_halfword(0x04000082, SOUNDCNT_FIFO_A_RESET | SOUNDCNT_FIFO_B_TIMER1);
+        assert_eq!(SOUNDCNT_FIFO_B_TIMER1, mem.get_halfword(0x04000082));
+        mem.idle(1);
+        mem.run_events();
+        assert_eq!([2, 0], mem.direct_sound_output());
+    }
+
+    #[test]
+    fn test_immediate_dma() {
+        let mut mem = Memory::new();
+        mem.set_halfword(0x04000200, Interrupt::Dma3.bit());
+        mem.set_word(0x02000000, 0x11223344);
+        mem.set_word(0x02000004, 0x55667788);
+
+        // 2 words from EWRAM to IWRAM, with its IRQ
+        mem.set_word(0x040000D4, 0x02000000);
+        mem.set_word(0x040000D8, 0x03000000);
+        mem.set_halfword(0x040000DC, 2);
+        mem.set_halfword(0x040000DE, 0xC400);
+
+        mem.idle(DMA_START_DELAY as u32);
+        let start = mem.cycles();
+        mem.run_events();
+
+        assert_eq!(0x55667788, mem.get_word(0x03000004));
+        assert_eq!(0, mem.get_halfword(0x040000DE) & 0x8000);
+        assert_eq!(Interrupt::Dma3.bit(), mem.get_halfword(0x04000202));
+
+        // Each EWRAM word takes 6 cycles each way, plus 2 to start and end
+        assert_eq!(start + 2 * (6 + 1) + 2, mem.cycles());
+    }
+
+    #[test]
+    fn test_hblank_dma_repeats() {
+        let mut mem = Memory::new();
+        mem.set_halfword(0x02000000, 0x1234);
+        mem.set_halfword(0x02000002, 0x5678);
+
+        // A halfword a line into palette RAM, reloading the destination each time
+        mem.set_word(0x040000B0, 0x02000000);
+        mem.set_word(0x040000B4, 0x05000000);
+        mem.set_halfword(0x040000B8, 1);
+        mem.set_halfword(0x040000BA, 0xA260);
+
+        mem.idle(HDRAW_CYCLES as u32);
+        mem.run_events();
+        assert_eq!(0x1234, mem.get_halfword(0x05000000));
+
+        mem.idle(SCANLINE_CYCLES as u32);
+        mem.run_events();
+        assert_eq!(0x5678, mem.get_halfword(0x05000000));
+        assert_ne!(0, mem.get_halfword(0x040000BA) & 0x8000);
+    }
+
+    #[test]
+    fn test_dma_refills_direct_sound() {
+        let mut mem = Memory::new();
+        mem.set_halfword(0x04000084, SOUNDCNT_MASTER_ENABLE);
+        for i in 0..16 {
+            mem.set_byte(0x02000000 + i, i as u8 + 1);
+        }
+
+        // DMA 1 feeding FIFO A, which is played on timer 0 overflowing every 256 cycles
+        mem.set_word(0x040000BC, 0x02000000);
+        mem.set_word(0x040000C0, 0x040000A0);
+        mem.set_halfword(0x040000C6, 0xB600);
+        mem.set_halfword(0x04000100, 0xFF00);
+        mem.set_halfword(0x04000102, 0x0080);
+
+        mem.idle(0x100);
+        mem.run_events();
+        mem.idle(0x100);
+        mem.run_events();
+
+        // The first overflow found the FIFO empty, so 16 bytes were fetched for the next
+        assert_eq!([1, 0], mem.direct_sound_output());
+
+        mem.idle(0x100);
+        mem.run_events();
+        assert_eq!([2, 0], mem.direct_sound_output());
+        assert_ne!(0, mem.get_halfword(0x040000C6) & 0x8000);
+    }
+
+    #[test]
+    fn test_audio_samples() {
+        let mut mem = Memory::new();
+        assert_eq!(HDRAW_CYCLES, mem.next_event());
+
+        // Full volume A on the left
+        mem.set_halfword(0x04000082, 0x0204);
+        mem.set_halfword(0x04000084, SOUNDCNT_MASTER_ENABLE);
+        assert_eq!(SAMPLE_CYCLES, mem.next_event());
+        mem.direct_sound.push(0, 8);
+        mem.direct_sound.timer_overflow(0);
+
+        mem.idle(2 * SAMPLE_CYCLES as u32);
+        mem.run_events();
+        assert_eq!(vec![[32 << 5, 0]; 2], mem.take_audio_samples());
+
+        // Turning sound off stops the sampling
+        mem.set_halfword(0x04000084, 0);
+        mem.idle(SAMPLE_CYCLES as u32);
+        mem.run_events();
+        assert!(mem.take_audio_samples().is_empty());
+    }
+
+    #[test]
+    fn test_swap_is_locked_on_the_bus() {
+        let accesses = Rc::new(RefCell::new(Vec::new()));
+        let seen = accesses.clone();
+
+        let mut mem = Memory::new();
+        mem.set_watcher(Some(Box::new(move |access| seen.borrow_mut().push(access))));
+
+        mem.read_32(0x03000000, Access::NonSequential, AccessKind::Data);
+        mem.swap_8(0x03000001, 0x12);
+
+        let access = |addr, width, write, locked| DataAccess {
+            addr,
+            width,
+            write,
+            locked,
+        };
+        assert_eq!(
+            vec![
+                access(0x03000000, 32, false, false),
+                access(0x03000001, 8, false, true),
+                access(0x03000001, 8, true, true),
+            ],
+            *accesses.borrow()
+        );
+        assert!(!mem.is_locked());
+    }
+
+    #[test]
+    fn test_swap_sees_open_bus_and_bios_protection() {
+        let mut mem = Memory::new_with_bios_and_rom(vec![0xAA; 0x4000], vec![]);
+        mem.set_word(0x03000000, 0xe1001091);
+        mem.read_32(0x03000000, Access::NonSequential, AccessKind::Opcode);
+
+        // Swaps read what loads do, from outside the BIOS only its last fetched opcode
+        let ldr = mem.read_32(0x10000000, Access::NonSequential, AccessKind::Data);
+        assert_eq!(0xe1001091, ldr);
+        assert_eq!(ldr, mem.swap_32(0x10000000, 0));
+
+        let ldr = mem.read_32(0x00000000, Access::NonSequential, AccessKind::Data);
+        assert_ne!(0xAAAAAAAA, ldr);
+        assert_eq!(ldr, mem.swap_32(0x00000000, 0));
     }
 }
@@ -0,0 +1,108 @@
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+
+// Something a peripheral has to do at a known clock cycle
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Event {
+    HBlank,         // The visible part of a scanline is done
+    HDraw,          // The next scanline starts, which is where VBlank starts and ends
+    SerialTransfer, // A transfer clocked by the GBA has shifted out all its bits
+    TimerOverflow(usize),
+    DmaStart(usize), // A triggered DMA channel gets the bus
+    AudioSample,     // The sound output is sampled for the frontend
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct Entry {
+    at: u64,
+    order: u64, // Events due on the same cycle run in the order they were scheduled
+    event: Event,
+}
+
+// BinaryHeap is a max-heap, so the earliest entry has to compare as the greatest
+impl Ord for Entry {
+    fn cmp(&self, other: &Entry) -> Ordering {
+        other
+            .at
+            .cmp(&self.at)
+            .then_with(|| other.order.cmp(&self.order))
+    }
+}
+
+impl PartialOrd for Entry {
+    fn partial_cmp(&self, other: &Entry) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+// Timestamped events in the order they're due, so the cpu can run straight up to the next one
+// rather than ticking every peripheral each cycle
+#[derive(Debug, Default)]
+pub struct Scheduler {
+    events: BinaryHeap<Entry>,
+    scheduled: u64,
+}
+
+impl Scheduler {
+    pub fn schedule(&mut self, at: u64, event: Event) {
+        self.events.push(Entry {
+            at,
+            order: self.scheduled,
+            event,
+        });
+        self.scheduled += 1;
+    }
+
+    // Removes every pending occurrence of the event
+    pub fn cancel(&mut self, event: Event) {
+        self.events.retain(|entry| entry.event != event);
+    }
+
+    pub fn next_at(&self) -> Option<u64> {
+        self.events.peek().map(|entry| entry.at)
+    }
+
+    // The earliest event due by now, with the cycle it was due at
+    pub fn pop_due(&mut self, now: u64) -> Option<(u64, Event)> {
+        match self.events.peek() {
+            Some(entry) if entry.at <= now => {
+                self.events.pop().map(|entry| (entry.at, entry.event))
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_events_in_time_order() {
+        let mut scheduler = Scheduler::default();
+
+        scheduler.schedule(100, Event::HDraw);
+        scheduler.schedule(50, Event::HBlank);
+        scheduler.schedule(100, Event::SerialTransfer);
+
+        assert_eq!(Some(50), scheduler.next_at());
+        assert_eq!(None, scheduler.pop_due(49));
+        assert_eq!(Some((50, Event::HBlank)), scheduler.pop_due(120));
+
+        // Ties keep the order they were scheduled in
+        assert_eq!(Some((100, Event::HDraw)), scheduler.pop_due(120));
+        assert_eq!(Some((100, Event::SerialTransfer)), scheduler.pop_due(120));
+        assert_eq!(None, scheduler.pop_due(120));
+    }
+
+    #[test]
+    fn test_cancel() {
+        let mut scheduler = Scheduler::default();
+
+        scheduler.schedule(10, Event::SerialTransfer);
+        scheduler.schedule(20, Event::HBlank);
+        scheduler.cancel(Event::SerialTransfer);
+
+        assert_eq!(Some(20), scheduler.next_at());
+    }
+}
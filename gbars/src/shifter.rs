@@ -0,0 +1,267 @@
+use crate::cpu::Register;
+use crate::instruction::read_register;
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum ShiftType {
+    Lsl,
+    Lsr,
+    Asr,
+    Ror,
+}
+
+// The 8-bit shift field of a register operand (instruction bits 11-4)
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Shift {
+    Immediate {
+        shift_type: ShiftType,
+        amount: u8,
+    }, // 5 bits
+    Register {
+        shift_type: ShiftType,
+        register: Register,
+    },
+}
+
+impl Shift {
+    pub fn decode(field: u8) -> Shift {
+        let shift_type = match (field >> 1) & 0b11 {
+            0b00 => ShiftType::Lsl,
+            0b01 => ShiftType::Lsr,
+            0b10 => ShiftType::Asr,
+            _ => ShiftType::Ror,
+        };
+
+        if field & 0b1 != 0 {
+            Shift::Register {
+                shift_type,
+                register: read_register(field >> 4),
+            }
+        } else {
+            Shift::Immediate {
+                shift_type,
+                amount: field >> 3,
+            }
+        }
+    }
+}
+
+// Shift by a 5-bit amount encoded in the instruction.
+// Returns the shifted value and the shifter carry out
+pub fn shift_immediate(shift_type: ShiftType, value: u32, amount: u8, carry: bool) -> (u32, bool) {
+    let amount = amount as u32 & 0b11111;
+
+    match shift_type {
+        ShiftType::Lsl => {
+            if amount == 0 {
+                (value, carry)
+            } else {
+                (value << amount, bit(value, 32 - amount))
+            }
+        }
+        ShiftType::Lsr => {
+            // LSR #0 encodes LSR #32
+            if amount == 0 {
+                (0, bit(value, 31))
+            } else {
+                (value >> amount, bit(value, amount - 1))
+            }
+        }
+        ShiftType::Asr => {
+            // ASR #0 encodes ASR #32
+            if amount == 0 {
+                (((value as i32) >> 31) as u32, bit(value, 31))
+            } else {
+                (((value as i32) >> amount) as u32, bit(value, amount - 1))
+            }
+        }
+        ShiftType::Ror => {
+            // ROR #0 encodes RRX, a 33-bit rotate through carry
+            if amount == 0 {
+                (((carry as u32) << 31) | (value >> 1), bit(value, 0))
+            } else {
+                (value.rotate_right(amount), bit(value, amount - 1))
+            }
+        }
+    }
+}
+
+// Shift by the bottom byte of a register.
+// Returns the shifted value and the shifter carry out
+pub fn shift_register(shift_type: ShiftType, value: u32, amount: u8, carry: bool) -> (u32, bool) {
+    let amount = amount as u32;
+
+    if amount == 0 {
+        return (value, carry);
+    }
+
+    match shift_type {
+        ShiftType::Lsl => match amount {
+            1..=31 => (value << amount, bit(value, 32 - amount)),
+            32 => (0, bit(value, 0)),
+            _ => (0, false),
+        },
+        ShiftType::Lsr => match amount {
+            1..=31 => (value >> amount, bit(value, amount - 1)),
+            32 => (0, bit(value, 31)),
+            _ => (0, false),
+        },
+        ShiftType::Asr => match amount {
+            1..=31 => (((value as i32) >> amount) as u32, bit(value, amount - 1)),
+            _ => (((value as i32) >> 31) as u32, bit(value, 31)),
+        },
+        ShiftType::Ror => {
+            let amount = amount & 0b11111;
+
+            if amount == 0 {
+                (value, bit(value, 31))
+            } else {
+                (value.rotate_right(amount), bit(value, amount - 1))
+            }
+        }
+    }
+}
+
+// An 8-bit immediate rotated right by twice the 4-bit rotate field.
+// Returns the value and the shifter carry out
+pub fn rotate_immediate(value: u8, rotate: u8, carry: bool) -> (u32, bool) {
+    if rotate == 0 {
+        (value as u32, carry)
+    } else {
+        let result = (value as u32).rotate_right(rotate as u32 * 2);
+
+        (result, bit(result, 31))
+    }
+}
+
+fn bit(value: u32, bit: u32) -> bool {
+    (value >> bit) & 0b1 != 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_shift() {
+        // r2, lsl #3
+        assert_eq!(
+            Shift::Immediate {
+                shift_type: ShiftType::Lsl,
+                amount: 3
+            },
+            Shift::decode(0b0001_1000)
+        );
+
+        // r1, asr r5
+        assert_eq!(
+            Shift::Register {
+                shift_type: ShiftType::Asr,
+                register: Register::R5
+            },
+            Shift::decode(0b0101_0101)
+        );
+    }
+
+    #[test]
+    fn test_immediate_special_encodings() {
+        // LSL #0 passes the value and carry through
+        assert_eq!(
+            (0x80000001, true),
+            shift_immediate(ShiftType::Lsl, 0x80000001, 0, true)
+        );
+
+        // LSR #0 is LSR #32
+        assert_eq!(
+            (0, true),
+            shift_immediate(ShiftType::Lsr, 0x80000000, 0, false)
+        );
+
+        // ASR #0 is ASR #32
+        assert_eq!(
+            (0xffffffff, true),
+            shift_immediate(ShiftType::Asr, 0x80000000, 0, false)
+        );
+        assert_eq!(
+            (0, false),
+            shift_immediate(ShiftType::Asr, 0x7fffffff, 0, true)
+        );
+
+        // ROR #0 is RRX
+        assert_eq!(
+            (0x80000000, true),
+            shift_immediate(ShiftType::Ror, 0x1, 0, true)
+        );
+        assert_eq!(
+            (0x00000001, false),
+            shift_immediate(ShiftType::Ror, 0x2, 0, false)
+        );
+    }
+
+    #[test]
+    fn test_immediate_carry_out() {
+        assert_eq!(
+            (0x2, true),
+            shift_immediate(ShiftType::Lsl, 0x80000001, 1, false)
+        );
+        assert_eq!(
+            (0x40000000, true),
+            shift_immediate(ShiftType::Lsr, 0x80000001, 1, false)
+        );
+        assert_eq!(
+            (0xc0000000, false),
+            shift_immediate(ShiftType::Asr, 0x80000000, 1, true)
+        );
+        assert_eq!(
+            (0x80000000, true),
+            shift_immediate(ShiftType::Ror, 0x1, 1, false)
+        );
+    }
+
+    #[test]
+    fn test_register_amounts() {
+        // A zero amount leaves value and carry untouched for every type
+        assert_eq!(
+            (0x1234, true),
+            shift_register(ShiftType::Lsr, 0x1234, 0, true)
+        );
+        assert_eq!(
+            (0x1234, false),
+            shift_register(ShiftType::Ror, 0x1234, 0, false)
+        );
+
+        assert_eq!((0, true), shift_register(ShiftType::Lsl, 0x1, 32, false));
+        assert_eq!(
+            (0, false),
+            shift_register(ShiftType::Lsl, 0xffffffff, 33, true)
+        );
+        assert_eq!(
+            (0, true),
+            shift_register(ShiftType::Lsr, 0x80000000, 32, false)
+        );
+        assert_eq!(
+            (0, false),
+            shift_register(ShiftType::Lsr, 0xffffffff, 40, true)
+        );
+        assert_eq!(
+            (0xffffffff, true),
+            shift_register(ShiftType::Asr, 0x80000000, 200, false)
+        );
+
+        // ROR by a multiple of 32 leaves the value but sets carry from bit 31
+        assert_eq!(
+            (0x80000001, true),
+            shift_register(ShiftType::Ror, 0x80000001, 64, false)
+        );
+        assert_eq!(
+            (0xc0000000, true),
+            shift_register(ShiftType::Ror, 0x3, 34, false)
+        );
+    }
+
+    #[test]
+    fn test_rotate_immediate() {
+        assert_eq!((0xff, true), rotate_immediate(0xff, 0, true));
+        assert_eq!((0xf000000f, true), rotate_immediate(0xff, 2, false));
+        assert_eq!((0x3fc, false), rotate_immediate(0xff, 15, true));
+    }
+}
@@ -0,0 +1,139 @@
+use std::collections::VecDeque;
+
+use log::trace;
+
+const FIFO_CAPACITY: usize = 32;
+
+// Direct Sound asks for a refill once this many bytes have been played
+const FIFO_REFILL_LEVEL: usize = 16;
+
+// About a second of output at 32768Hz, past which samples the frontend hasn't taken are dropped
+const SAMPLE_BUFFER_CAPACITY: usize = 0x8000;
+
+// SOUNDCNT_H, per channel: full rather than half volume, then the right and left enables
+const VOLUME_FULL: [u16; 2] = [1 << 2, 1 << 3];
+const ENABLE_RIGHT: [u16; 2] = [1 << 8, 1 << 12];
+const ENABLE_LEFT: [u16; 2] = [1 << 9, 1 << 13];
+
+#[derive(Debug, Default)]
+struct Fifo {
+    samples: VecDeque<i8>,
+    output: i8, // The sample being played
+}
+
+// The two 8-bit Direct Sound channels, A and B, which play a sample from their FIFO each time
+// the timer they're clocked by overflows
+#[derive(Debug, Default)]
+pub struct DirectSound {
+    fifos: [Fifo; 2],
+    samples: Vec<[i16; 2]>, // Left and right output, waiting for the frontend
+}
+
+impl DirectSound {
+    pub fn push(&mut self, channel: usize, sample: i8) {
+        let fifo = &mut self.fifos[channel];
+
+        if fifo.samples.len() < FIFO_CAPACITY {
+            fifo.samples.push_back(sample);
+        } else {
+            trace!("Direct Sound FIFO {} full", channel);
+        }
+    }
+
+    pub fn reset(&mut self, channel: usize) {
+        self.fifos[channel].samples.clear();
+    }
+
+    // Moves on to the next sample. Returns true when the FIFO is low enough to want a refill
+    pub fn timer_overflow(&mut self, channel: usize) -> bool {
+        let fifo = &mut self.fifos[channel];
+
+        if let Some(sample) = fifo.samples.pop_front() {
+            fifo.output = sample;
+        }
+
+        fifo.samples.len() <= FIFO_REFILL_LEVEL
+    }
+
+    pub fn output(&self) -> [i8; 2] {
+        [self.fifos[0].output, self.fifos[1].output]
+    }
+
+    // Mixes what both channels are playing into a left and right sample, as SOUNDCNT_H asks
+    pub fn sample(&mut self, control: u16) {
+        let mut mixed = [0i16; 2];
+
+        for channel in 0..2 {
+            let volume = if control & VOLUME_FULL[channel] != 0 {
+                2
+            } else {
+                1
+            };
+            let output = (self.fifos[channel].output as i16) << volume;
+
+            for (side, enable) in [ENABLE_LEFT, ENABLE_RIGHT].iter().enumerate() {
+                if control & enable[channel] != 0 {
+                    mixed[side] += output;
+                }
+            }
+        }
+
+        if self.samples.len() < SAMPLE_BUFFER_CAPACITY {
+            // Both channels at full volume fill 10 bits, scaled up to 16
+            self.samples.push([mixed[0] << 5, mixed[1] << 5]);
+        } else {
+            trace!("Sample buffer full");
+        }
+    }
+
+    pub fn take_samples(&mut self) -> Vec<[i16; 2]> {
+        std::mem::take(&mut self.samples)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fifo_playback() {
+        let mut sound = DirectSound::default();
+
+        for sample in 0..20 {
+            sound.push(0, sample);
+        }
+        sound.push(1, -5);
+
+        assert!(!sound.timer_overflow(0));
+        assert!(!sound.timer_overflow(0));
+        assert_eq!([1, 0], sound.output());
+
+        assert!(!sound.timer_overflow(0));
+        assert!(sound.timer_overflow(0));
+
+        // An empty FIFO keeps playing its last sample
+        assert!(sound.timer_overflow(1));
+        assert!(sound.timer_overflow(1));
+        assert_eq!([3, -5], sound.output());
+
+        sound.reset(0);
+        assert!(sound.timer_overflow(0));
+        assert_eq!([3, -5], sound.output());
+    }
+
+    #[test]
+    fn test_mixing() {
+        let mut sound = DirectSound::default();
+        sound.push(0, 100);
+        sound.push(1, -20);
+        sound.timer_overflow(0);
+        sound.timer_overflow(1);
+
+        // A at full volume on both sides, B at half volume on the right only
+        sound.sample(VOLUME_FULL[0] | ENABLE_LEFT[0] | ENABLE_RIGHT[0] | ENABLE_RIGHT[1]);
+        sound.sample(0);
+
+        assert_eq!(vec![[400 << 5, 360 << 5], [0, 0]], sound.take_samples());
+        assert!(sound.take_samples().is_empty());
+    }
+}
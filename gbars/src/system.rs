@@ -0,0 +1,24 @@
+use crate::bus::Bus;
+
+// The rest of the system, as the cpu sees it between instructions: the interrupt controller,
+// the low power states and the event scheduler. The defaults are a system with none of them
+pub trait System: Bus {
+    // The interrupt controller's request to the cpu, which takes it unless CPSR.I is set
+    fn irq_line(&mut self) -> bool {
+        false
+    }
+
+    // True while HALTCNT has put the cpu to sleep waiting for an interrupt
+    fn halted(&self) -> bool {
+        false
+    }
+
+    // Clock cycle the next scheduled event is due at, which the cpu can run up to without
+    // checking on the rest of the system
+    fn next_event(&self) -> u64 {
+        u64::MAX
+    }
+
+    // Runs every event that is due
+    fn run_events(&mut self) {}
+}
@@ -0,0 +1,174 @@
+// TMxCNT_H
+const TIMER_CASCADE: u16 = 1 << 2;
+const TIMER_IRQ: u16 = 1 << 6;
+const TIMER_ENABLE: u16 = 1 << 7;
+
+// Clock cycles per tick for each prescaler setting, as a shift
+const PRESCALER_SHIFTS: [u32; 4] = [0, 6, 8, 10];
+
+// One of the four 16-bit timers. Timers counting by themselves only store their counter as of
+// `since` and work out the live value from the clock, so they never need ticking.
+// Cascade timers count overflows of the timer before them instead
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Timer {
+    control: u16,
+    counter: u16,
+    since: u64,
+}
+
+impl Timer {
+    pub fn enabled(&self) -> bool {
+        self.control & TIMER_ENABLE != 0
+    }
+
+    pub fn cascade(&self) -> bool {
+        self.control & TIMER_CASCADE != 0
+    }
+
+    pub fn irq_enabled(&self) -> bool {
+        self.control & TIMER_IRQ != 0
+    }
+
+    fn ticking(&self) -> bool {
+        self.enabled() && !self.cascade()
+    }
+
+    fn shift(&self) -> u32 {
+        PRESCALER_SHIFTS[(self.control & 0b11) as usize]
+    }
+
+    // The live counter. An overflow that's due but hasn't been run yet has already reloaded
+    pub fn counter(&self, now: u64, reload: u16) -> u16 {
+        if !self.ticking() {
+            return self.counter;
+        }
+
+        let ticks = now.saturating_sub(self.since) >> self.shift();
+        let to_overflow = 0x10000 - self.counter as u64;
+
+        if ticks < to_overflow {
+            self.counter + ticks as u16
+        } else {
+            reload + ((ticks - to_overflow) % (0x10000 - reload as u64)) as u16
+        }
+    }
+
+    // Brings the counter up to date, keeping any part of a tick the prescaler has counted
+    fn sync(&mut self, now: u64) {
+        if self.ticking() {
+            let ticks = now.saturating_sub(self.since) >> self.shift();
+
+            self.counter = self.counter.wrapping_add(ticks as u16);
+            self.since += ticks << self.shift();
+        }
+    }
+
+    // The clock cycle the counter will next overflow at, if it counts by itself
+    fn next_overflow(&self) -> Option<u64> {
+        if self.ticking() {
+            Some(self.since + ((0x10000 - self.counter as u64) << self.shift()))
+        } else {
+            None
+        }
+    }
+
+    // Applies a TMxCNT_H write. Starting the timer loads the reload value into the counter, and
+    // the prescaler counts from now whenever it starts ticking, including on leaving cascade
+    pub fn set_control(&mut self, control: u16, reload: u16, now: u64) -> Option<u64> {
+        self.sync(now);
+
+        let was_ticking = self.ticking();
+
+        if !self.enabled() && control & TIMER_ENABLE != 0 {
+            self.counter = reload;
+        }
+
+        self.control = control;
+
+        if !was_ticking && self.ticking() {
+            self.since = now;
+        }
+
+        self.next_overflow()
+    }
+
+    // Reloads after overflowing at the given cycle, returning when the next overflow is due
+    pub fn overflow(&mut self, reload: u16, at: u64) -> Option<u64> {
+        self.counter = reload;
+        self.since = at;
+
+        self.next_overflow()
+    }
+
+    // Counts one overflow of the previous timer, returning true if this one overflows too
+    pub fn count_up(&mut self, reload: u16) -> bool {
+        if self.counter == 0xFFFF {
+            self.counter = reload;
+            true
+        } else {
+            self.counter += 1;
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_prescaled_counter() {
+        let mut timer = Timer::default();
+
+        // 64 cycles per tick, starting from 0xFF00
+        let overflow = timer.set_control(TIMER_ENABLE | 0b01, 0xFF00, 100);
+        assert_eq!(Some(100 + 0x100 * 64), overflow);
+
+        assert_eq!(0xFF00, timer.counter(163, 0xFF00));
+        assert_eq!(0xFF01, timer.counter(164, 0xFF00));
+
+        // Switching to 1 cycle per tick keeps the ticks counted so far
+        let overflow = timer.set_control(TIMER_ENABLE, 0xFF00, 100 + 64 * 0x10);
+        assert_eq!(Some(100 + 64 * 0x10 + 0xF0), overflow);
+
+        assert_eq!(Some(1000 + 0x100), timer.overflow(0xFF00, 1000));
+        assert_eq!(0xFF05, timer.counter(1005, 0xFF00));
+    }
+
+    #[test]
+    fn test_stopped_timer_keeps_value() {
+        let mut timer = Timer::default();
+
+        timer.set_control(TIMER_ENABLE, 0x1000, 0);
+        assert_eq!(None, timer.set_control(0, 0x1000, 0x20));
+
+        assert_eq!(0x1020, timer.counter(0x500, 0x1000));
+    }
+
+    #[test]
+    fn test_cascade() {
+        let mut timer = Timer::default();
+
+        assert_eq!(
+            None,
+            timer.set_control(TIMER_ENABLE | TIMER_CASCADE, 0xFFFE, 0)
+        );
+        assert!(!timer.count_up(0xFFFE));
+        assert!(timer.count_up(0xFFFE));
+        assert_eq!(0xFFFE, timer.counter(1000, 0xFFFE));
+    }
+
+    #[test]
+    fn test_leaving_cascade_starts_ticking_from_now() {
+        let mut timer = Timer::default();
+
+        timer.set_control(TIMER_ENABLE | TIMER_CASCADE, 0xFF00, 100);
+        timer.count_up(0xFF00);
+
+        // The cycles spent cascading don't count as ticks
+        let overflow = timer.set_control(TIMER_ENABLE, 0xFF00, 5000);
+        assert_eq!(Some(5000 + 0xFF), overflow);
+        assert_eq!(0xFF01, timer.counter(5000, 0xFF00));
+        assert_eq!(0xFF11, timer.counter(5000 + 0x10, 0xFF00));
+    }
+}
@@ -1,6 +1,5 @@
 use gbars::{Cpu, Memory};
 
-use env_logger;
 use object::{Object, ObjectSection};
 
 use std::fs;
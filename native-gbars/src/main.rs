@@ -3,10 +3,20 @@ use structopt::StructOpt;
 
 use std::fs;
 
+// 228 scanlines of 1232 cycles
+const CYCLES_PER_FRAME: u64 = 280896;
+
 #[derive(Debug, StructOpt)]
 struct Opt {
-    bios: String,
-    rom: String,
+    // Either `bios rom`, or just `rom`. Without a BIOS image, BIOS calls are emulated and the
+    // cartridge is started directly
+    #[structopt(name = "BIOS_OR_ROM")]
+    first: String,
+    rom: Option<String>,
+
+    // How long to run for, as there's no window to close yet
+    #[structopt(long, default_value = "60")]
+    frames: u32,
 }
 
 fn main() {
@@ -14,22 +24,30 @@ fn main() {
 
     let opt = Opt::from_args();
 
-    let startpc = 0x10000;
-    let decode_length = 100;
-
-    let bios_data = fs::read(opt.bios).expect("Unable to read bios file");
-    let rom_data = fs::read(opt.rom).expect("Unable to read rom file");
+    let (bios, rom) = match opt.rom {
+        Some(rom) => (Some(opt.first), rom),
+        None => (None, opt.first),
+    };
 
-    let mut mem = Memory::new_with_bios_and_rom(bios_data, rom_data);
+    let rom_data = fs::read(rom).expect("Unable to read rom file");
 
     let mut cpu = Cpu::new();
-    cpu.r15 = startpc;
 
-    loop {
-        if cpu.r15 > (startpc + decode_length) {
-            return;
+    // With a BIOS image the cpu starts at the reset vector, like the real thing
+    let mut mem = match bios {
+        Some(bios) => {
+            let bios_data = fs::read(bios).expect("Unable to read bios file");
+
+            Memory::new_with_bios_and_rom(bios_data, rom_data)
+        }
+        None => {
+            cpu.skip_bios();
+            cpu.set_hle_bios(true);
+            Memory::new_with_rom(rom_data)
         }
+    };
 
-        cpu.cycle(&mut mem);
+    for _ in 0..opt.frames {
+        cpu.run(&mut mem, CYCLES_PER_FRAME);
     }
 }